@@ -0,0 +1,477 @@
+use anyhow::{bail, Result};
+use cgmath::{prelude::*, Matrix4, Quaternion, Vector3};
+
+// Where a joint is relative to its parent
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct JointPose {
+    pub translation: Vector3<f32>,
+    pub rotation: Quaternion<f32>,
+    pub scale: Vector3<f32>,
+}
+
+impl JointPose {
+    pub fn matrix(&self) -> Matrix4<f32> {
+        Matrix4::from_translation(self.translation)
+            * Matrix4::from(self.rotation)
+            * Matrix4::from_nonuniform_scale(self.scale.x, self.scale.y, self.scale.z)
+    }
+}
+
+pub struct Joint {
+    pub parent: Option<usize>,
+    // Where the nodes above the skeleton put a root joint. Identity for every other joint
+    pub origin: Matrix4<f32>,
+    // The pose when no clip moves the joint
+    pub rest: JointPose,
+    // Takes a vertex from the model into the joint's space as it was when the mesh was bound to it
+    pub inverse_bind: Matrix4<f32>,
+}
+
+// The joints a skinned mesh's vertices are weighted to, in the order their indices refer to
+pub struct Skeleton {
+    joints: Vec<Joint>,
+    // every joint after its parent, so that parents are transformed first
+    order: Vec<usize>,
+}
+
+impl Skeleton {
+    pub fn new(joints: Vec<Joint>) -> Result<Self> {
+        let mut order = Vec::with_capacity(joints.len());
+        let mut placed = vec![false; joints.len()];
+
+        // Each round places the joints whose parents are placed, so a round that
+        // places nothing means the rest have a parent that never will be
+        while order.len() < joints.len() {
+            let before = order.len();
+
+            for (index, joint) in joints.iter().enumerate() {
+                if placed[index] {
+                    continue;
+                }
+
+                match joint.parent {
+                    Some(parent) if parent >= joints.len() => bail!(
+                        "joint {} has parent {}, past the last of {} joints",
+                        index,
+                        parent,
+                        joints.len()
+                    ),
+                    Some(parent) if !placed[parent] => continue,
+                    _ => {}
+                }
+
+                placed[index] = true;
+                order.push(index);
+            }
+
+            if order.len() == before {
+                bail!("the joints' parents go round in a loop");
+            }
+        }
+
+        Ok(Skeleton { joints, order })
+    }
+
+    pub fn joint_count(&self) -> usize {
+        self.joints.len()
+    }
+
+    pub fn rest_pose(&self) -> Vec<JointPose> {
+        self.joints.iter().map(|joint| joint.rest).collect()
+    }
+
+    // A matrix per joint that takes bound vertices to where the pose puts them
+    pub fn palette(&self, poses: &[JointPose]) -> Vec<Matrix4<f32>> {
+        let mut globals = vec![Matrix4::identity(); self.joints.len()];
+
+        for &index in self.order.iter() {
+            let joint = &self.joints[index];
+            let parent = joint.parent.map_or(joint.origin, |parent| globals[parent]);
+
+            globals[index] = parent * poses[index].matrix();
+        }
+
+        globals
+            .iter()
+            .zip(self.joints.iter())
+            .map(|(global, joint)| global * joint.inverse_bind)
+            .collect()
+    }
+
+    // The palette for a point in a clip, or for the rest pose without one
+    pub fn animate(&self, clip: Option<&Clip>, time: f32) -> Vec<Matrix4<f32>> {
+        let mut poses = self.rest_pose();
+
+        if let Some(clip) = clip {
+            clip.sample(time, &mut poses);
+        }
+
+        self.palette(&poses)
+    }
+}
+
+// How values are filled in between keyframes, as glTF names them
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interpolation {
+    // The previous keyframe's value until the next one
+    Step,
+    // Straight from one to the next, or along the sphere for rotations
+    Linear,
+    // A Hermite curve. Every keyframe has an in tangent, a value and an out tangent, in that order
+    CubicSpline,
+}
+
+pub enum Keyframes {
+    Translation(Vec<Vector3<f32>>),
+    Rotation(Vec<Quaternion<f32>>),
+    Scale(Vec<Vector3<f32>>),
+}
+
+impl Keyframes {
+    fn len(&self) -> usize {
+        match self {
+            Keyframes::Translation(values) | Keyframes::Scale(values) => values.len(),
+            Keyframes::Rotation(values) => values.len(),
+        }
+    }
+}
+
+// One property of one joint over time
+pub struct Channel {
+    pub joint: usize,
+    pub interpolation: Interpolation,
+    // seconds, in increasing order
+    pub times: Vec<f32>,
+    pub keyframes: Keyframes,
+}
+
+pub struct Clip {
+    pub name: String,
+    // when the last keyframe of any channel is
+    pub duration: f32,
+    channels: Vec<Channel>,
+}
+
+impl Clip {
+    pub fn new(name: &str, channels: Vec<Channel>, joint_count: usize) -> Result<Self> {
+        for channel in channels.iter() {
+            if channel.joint >= joint_count {
+                bail!(
+                    "a channel animates joint {}, past the last of {} joints",
+                    channel.joint,
+                    joint_count
+                );
+            }
+
+            if channel.times.is_empty() {
+                bail!("a channel of joint {} has no keyframes", channel.joint);
+            }
+
+            if channel.times.iter().any(|time| !time.is_finite())
+                || channel.times.windows(2).any(|pair| pair[0] > pair[1])
+            {
+                bail!(
+                    "the keyframe times of joint {} don't only go forward",
+                    channel.joint
+                );
+            }
+
+            let values_per_keyframe = match channel.interpolation {
+                Interpolation::CubicSpline => 3,
+                _ => 1,
+            };
+
+            if channel.keyframes.len() != channel.times.len() * values_per_keyframe {
+                bail!(
+                    "a channel of joint {} has {} values for {} keyframes",
+                    channel.joint,
+                    channel.keyframes.len(),
+                    channel.times.len()
+                );
+            }
+        }
+
+        let duration = channels
+            .iter()
+            .map(|channel| channel.times[channel.times.len() - 1])
+            .fold(0.0, f32::max);
+
+        Ok(Clip {
+            name: name.to_string(),
+            duration,
+            channels,
+        })
+    }
+
+    // Move the joints the clip animates to where they are at the given time,
+    // which is clamped to the clip. Joints it doesn't animate are left as they are
+    pub fn sample(&self, time: f32, poses: &mut [JointPose]) {
+        for channel in self.channels.iter() {
+            let pose = &mut poses[channel.joint];
+            let times = &channel.times;
+            let interpolation = channel.interpolation;
+
+            match &channel.keyframes {
+                Keyframes::Translation(values) => {
+                    pose.translation = sample(times, values, interpolation, time)
+                }
+                Keyframes::Scale(values) => pose.scale = sample(times, values, interpolation, time),
+                Keyframes::Rotation(values) => {
+                    pose.rotation = match interpolation {
+                        Interpolation::Linear => {
+                            let (previous, next, t) = locate(times, time);
+
+                            values[previous].slerp(values[next], t)
+                        }
+                        _ => sample(times, values, interpolation, time),
+                    }
+                    .normalize()
+                }
+            }
+        }
+    }
+}
+
+// Where a playback time falls in a clip. Looping clips wrap around, the rest stop at their end
+pub fn clip_time(time: f32, duration: f32, looping: bool) -> f32 {
+    if duration <= 0.0 {
+        0.0
+    } else if looping {
+        time.rem_euclid(duration)
+    } else {
+        time.clamp(0.0, duration)
+    }
+}
+
+// The keyframes on either side of a time, and how far between them it is.
+// Both are the same keyframe before the first and after the last
+fn locate(times: &[f32], time: f32) -> (usize, usize, f32) {
+    let next = times.partition_point(|keyframe| *keyframe <= time);
+
+    if next == 0 {
+        return (0, 0, 0.0);
+    }
+
+    if next == times.len() {
+        return (next - 1, next - 1, 0.0);
+    }
+
+    let previous = next - 1;
+    let span = times[next] - times[previous];
+
+    (previous, next, (time - times[previous]) / span)
+}
+
+fn sample<V>(times: &[f32], values: &[V], interpolation: Interpolation, time: f32) -> V
+where
+    V: VectorSpace<Scalar = f32>,
+{
+    let (previous, next, t) = locate(times, time);
+
+    match interpolation {
+        Interpolation::Step => values[previous],
+        Interpolation::Linear => values[previous].lerp(values[next], t),
+        Interpolation::CubicSpline => {
+            let value = |keyframe: usize| values[keyframe * 3 + 1];
+
+            if previous == next {
+                return value(previous);
+            }
+
+            // the tangents are per second, so they're scaled to the span between the keyframes
+            let span = times[next] - times[previous];
+            let out_tangent = values[previous * 3 + 2] * span;
+            let in_tangent = values[next * 3] * span;
+            let (t2, t3) = (t * t, t * t * t);
+
+            value(previous) * (2.0 * t3 - 3.0 * t2 + 1.0)
+                + out_tangent * (t3 - 2.0 * t2 + t)
+                + value(next) * (-2.0 * t3 + 3.0 * t2)
+                + in_tangent * (t3 - t2)
+        }
+    }
+}
+
+//
+// Tests
+//
+
+#[cfg(test)]
+mod tests {
+    use crate::animation::{
+        clip_time, Channel, Clip, Interpolation, Joint, JointPose, Keyframes, Skeleton,
+    };
+    use crate::geometry::tests::assert_close;
+    use cgmath::{prelude::*, Matrix4, Quaternion, Rad, Vector3};
+
+    const REST: JointPose = JointPose {
+        translation: Vector3::new(0.0, 0.0, 0.0),
+        rotation: Quaternion::new(1.0, 0.0, 0.0, 0.0),
+        scale: Vector3::new(1.0, 1.0, 1.0),
+    };
+
+    fn translation_clip(interpolation: Interpolation, values: Vec<Vector3<f32>>) -> Clip {
+        let channel = Channel {
+            joint: 0,
+            interpolation,
+            times: vec![1.0, 3.0],
+            keyframes: Keyframes::Translation(values),
+        };
+
+        Clip::new("move", vec![channel], 1).unwrap()
+    }
+
+    fn translation_at(clip: &Clip, time: f32) -> Vector3<f32> {
+        let mut poses = [REST];
+
+        clip.sample(time, &mut poses);
+        poses[0].translation
+    }
+
+    #[test]
+    fn test_step_and_linear_sampling() {
+        let keyframes = vec![Vector3::new(0.0, 0.0, 0.0), Vector3::new(4.0, 0.0, 0.0)];
+        let step = translation_clip(Interpolation::Step, keyframes.clone());
+        let linear = translation_clip(Interpolation::Linear, keyframes);
+
+        assert_eq!(linear.duration, 3.0);
+        assert_close(translation_at(&step, 2.5), Vector3::new(0.0, 0.0, 0.0));
+        assert_close(translation_at(&step, 3.0), Vector3::new(4.0, 0.0, 0.0));
+        assert_close(translation_at(&linear, 1.5), Vector3::new(1.0, 0.0, 0.0));
+
+        // Clamped to the first and last keyframes outside of them
+        assert_close(translation_at(&linear, 0.0), Vector3::new(0.0, 0.0, 0.0));
+        assert_close(translation_at(&linear, 10.0), Vector3::new(4.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn test_cubic_sampling() {
+        let zero = Vector3::new(0.0, 0.0, 0.0);
+        let flat = translation_clip(
+            Interpolation::CubicSpline,
+            vec![zero, zero, zero, zero, Vector3::new(0.0, 2.0, 0.0), zero],
+        );
+
+        // With flat tangents it eases in and out, so it's halfway at the halfway point
+        assert_close(translation_at(&flat, 2.0), Vector3::new(0.0, 1.0, 0.0));
+        assert!(translation_at(&flat, 1.5).y < 0.5);
+        assert_close(translation_at(&flat, 3.0), Vector3::new(0.0, 2.0, 0.0));
+
+        // A steep out tangent overshoots what a straight line would do
+        let steep = translation_clip(
+            Interpolation::CubicSpline,
+            vec![
+                zero,
+                zero,
+                Vector3::new(0.0, 4.0, 0.0),
+                zero,
+                Vector3::new(0.0, 2.0, 0.0),
+                zero,
+            ],
+        );
+
+        assert!(translation_at(&steep, 1.5).y > 0.5);
+    }
+
+    #[test]
+    fn test_rotation_sampling() {
+        let channel = Channel {
+            joint: 0,
+            interpolation: Interpolation::Linear,
+            times: vec![0.0, 1.0],
+            keyframes: Keyframes::Rotation(vec![
+                Quaternion::from_angle_y(Rad(0.0)),
+                Quaternion::from_angle_y(Rad(2.0)),
+            ]),
+        };
+        let clip = Clip::new("turn", vec![channel], 1).unwrap();
+        let mut poses = [REST];
+
+        clip.sample(0.5, &mut poses);
+
+        // Along the sphere, so it turns at an even rate and stays a rotation
+        assert!((poses[0].rotation.magnitude() - 1.0).abs() < 0.0001);
+        assert_close(
+            poses[0].rotation * Vector3::unit_z(),
+            Quaternion::from_angle_y(Rad(1.0)) * Vector3::unit_z(),
+        );
+    }
+
+    #[test]
+    fn test_malformed_clips() {
+        let error = |joint: usize, times: Vec<f32>, values: usize| {
+            let channel = Channel {
+                joint,
+                interpolation: Interpolation::CubicSpline,
+                times,
+                keyframes: Keyframes::Scale(vec![Vector3::new(1.0, 1.0, 1.0); values]),
+            };
+
+            Clip::new("bad", vec![channel], 2)
+                .err()
+                .unwrap()
+                .to_string()
+        };
+
+        assert!(error(2, vec![0.0], 3).contains("joint 2, past the last of 2"));
+        assert!(error(0, vec![], 0).contains("no keyframes"));
+        assert!(error(0, vec![1.0, 0.0], 6).contains("don't only go forward"));
+        assert!(error(0, vec![0.0, 1.0], 2).contains("2 values for 2 keyframes"));
+    }
+
+    #[test]
+    fn test_skeleton_palette() {
+        // A child joint listed before its parent, one unit above it, bound in that rest pose
+        let up = Vector3::new(0.0, 1.0, 0.0);
+        let joints = vec![
+            Joint {
+                parent: Some(1),
+                origin: Matrix4::identity(),
+                rest: JointPose {
+                    translation: up,
+                    ..REST
+                },
+                inverse_bind: Matrix4::from_translation(-up * 3.0),
+            },
+            Joint {
+                parent: None,
+                origin: Matrix4::from_translation(up * 2.0),
+                rest: REST,
+                inverse_bind: Matrix4::from_translation(-up * 2.0),
+            },
+        ];
+        let skeleton = Skeleton::new(joints).unwrap();
+
+        // The rest pose is the bind pose, so nothing moves
+        for matrix in skeleton.palette(&skeleton.rest_pose()) {
+            assert_eq!(matrix, Matrix4::identity());
+        }
+
+        // Turning the parent swings the child around it
+        let mut poses = skeleton.rest_pose();
+
+        poses[1].rotation = Quaternion::from_angle_z(Rad(std::f32::consts::FRAC_PI_2));
+
+        let palette = skeleton.palette(&poses);
+        let child_vertex = palette[0].transform_point(cgmath::Point3::new(0.0, 3.0, 0.0));
+
+        assert_close(child_vertex.to_vec(), Vector3::new(-1.0, 2.0, 0.0));
+
+        let looped = Skeleton::new(vec![Joint {
+            parent: Some(0),
+            origin: Matrix4::identity(),
+            rest: REST,
+            inverse_bind: Matrix4::identity(),
+        }]);
+
+        assert!(looped.err().unwrap().to_string().contains("loop"));
+    }
+
+    #[test]
+    fn test_clip_time() {
+        assert_eq!(clip_time(5.0, 2.0, true), 1.0);
+        assert_eq!(clip_time(-0.5, 2.0, true), 1.5);
+        assert_eq!(clip_time(5.0, 2.0, false), 2.0);
+        assert_eq!(clip_time(5.0, 0.0, true), 0.0);
+    }
+}
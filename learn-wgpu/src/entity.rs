@@ -1,9 +1,65 @@
-use crate::steering::{Kinematic, KinematicProps, SteeringOutput};
+use crate::path::Path;
+use crate::steering::{Kinematic, KinematicProps, SteeringOutput, WanderProps};
 
 use cgmath::{prelude::*, Quaternion, Vector3, Zero};
+use serde::{Deserialize, Serialize};
 
+// Where an entity is, for drawing it between two simulation ticks
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Transform {
+    pub position: Vector3<f32>,
+    pub orientation: Quaternion<f32>,
+}
+
+impl Transform {
+    pub fn interpolate(&self, next: &Transform, alpha: f32) -> Transform {
+        Transform {
+            position: self.position.lerp(next.position, alpha),
+            // slerp takes the shortest way around
+            orientation: self.orientation.slerp(next.orientation, alpha),
+        }
+    }
+}
+
+// How far a point or spot light reaches when the scene doesn't say
+pub const DEFAULT_LIGHT_RANGE: f32 = 25.0;
+
+// How a light shines. The shaders have a matching constant for each kind
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum LightKind {
+    // In every direction, fading out to nothing at range
+    Point {
+        range: f32,
+    },
+    // From infinitely far away along one direction, like a sun. Doesn't move
+    Directional {
+        direction: Vector3<f32>,
+    },
+    // A cone around a direction, full strength within the inner angle and
+    // fading out towards the outer one
+    Spot {
+        range: f32,
+        direction: Vector3<f32>,
+        inner_cone_degrees: f32,
+        outer_cone_degrees: f32,
+    },
+}
+
+impl Default for LightKind {
+    fn default() -> Self {
+        LightKind::Point {
+            range: DEFAULT_LIGHT_RANGE,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Light {
+    pub kind: LightKind,
     pub position: Vector3<f32>,
+    // The position at the start of the last simulation tick
+    pub previous_position: Vector3<f32>,
     pub velocity: Vector3<f32>,
     pub color: [f32; 3],
     pub chase_target_id: Option<u16>,
@@ -12,19 +68,34 @@ pub struct Light {
 impl Light {
     pub fn new(position: Vector3<f32>, color: [f32; 3]) -> Light {
         Light {
+            kind: LightKind::default(),
             position,
+            previous_position: position,
             velocity: Vector3::zero(),
             color,
             chase_target_id: None,
         }
     }
 
-    pub fn update_color(&mut self) {
-        self.color = [
-            distance_to_color_intensity(self.position.x),
-            distance_to_color_intensity(self.position.y),
-            distance_to_color_intensity(self.position.z),
-        ];
+    // Directional lights have no position that matters, so they neither chase
+    // ships nor get in their way
+    pub fn is_directional(&self) -> bool {
+        matches!(self.kind, LightKind::Directional { .. })
+    }
+
+    // Spot lights point where they are told to
+    pub fn aim_at(&mut self, target: Vector3<f32>) {
+        if let LightKind::Spot { direction, .. } = &mut self.kind {
+            let to_target = target - self.position;
+
+            if to_target.magnitude2() > 0.0 {
+                *direction = to_target.normalize();
+            }
+        }
+    }
+
+    pub fn interpolated_position(&self, alpha: f32) -> Vector3<f32> {
+        self.previous_position.lerp(self.position, alpha)
     }
 }
 
@@ -36,12 +107,15 @@ impl Kinematic for Light {
             velocity: self.velocity,
             rotation: cgmath::Vector3::zero(),
             max_acceleration: 1.5,
+            max_speed: 0.95,
+            max_angular_acceleration: 0.0,
+            max_rotation: 0.0,
         }
     }
 
     fn update(&mut self, steering: SteeringOutput, delta: std::time::Duration) {
         let dt = delta.as_secs_f32();
-        let max_speed = 0.95;
+        let max_speed = self.props().max_speed;
 
         // a Light can only move, not rotate
         self.position += self.velocity * dt;
@@ -50,25 +124,76 @@ impl Kinematic for Light {
             .map_or(self.velocity, |linear| self.velocity + (linear * dt));
 
         if self.velocity.magnitude() > max_speed {
-            self.velocity.normalize();
-            self.velocity *= max_speed;
+            self.velocity = self.velocity.normalize() * max_speed;
         }
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum SpaceshipState {
     Fleeing,
     Wandering,
+    Patrolling,
     Idle,
 }
 
+// Which animation clip of its model an entity plays. The clip is looked up by name
+// when drawing, since the world doesn't know about models
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AnimationPlayback {
+    pub clip: String,
+    // Seconds into the clip, not yet wrapped around or clamped to its length
+    pub time: f32,
+    // The time at the start of the last simulation tick
+    pub previous_time: f32,
+    // Negative plays it backwards
+    pub speed: f32,
+    pub looping: bool,
+    pub paused: bool,
+}
+
+impl AnimationPlayback {
+    pub fn new(clip: &str, speed: f32, looping: bool) -> Self {
+        AnimationPlayback {
+            clip: clip.to_string(),
+            time: 0.0,
+            previous_time: 0.0,
+            speed,
+            looping,
+            paused: false,
+        }
+    }
+
+    pub fn advance(&mut self, dt: std::time::Duration) {
+        self.previous_time = self.time;
+
+        if !self.paused {
+            self.time += dt.as_secs_f32() * self.speed;
+        }
+    }
+
+    pub fn interpolated_time(&self, alpha: f32) -> f32 {
+        self.previous_time + (self.time - self.previous_time) * alpha
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Spaceship {
     pub id: u16,
     pub position: Vector3<f32>,
     pub orientation: Quaternion<f32>,
     pub velocity: Vector3<f32>,
     pub rotation: Vector3<f32>,
+    // The transform at the start of the last simulation tick
+    pub previous: Transform,
     pub state: SpaceshipState,
+    pub wander: WanderProps,
+    pub patrol_path: Option<Path>,
+    // Turn the ship towards its direction of travel whenever nothing else steers its rotation
+    pub look_where_you_are_going: bool,
+    // Drawn in the rest pose without one
+    #[serde(default)]
+    pub animation: Option<AnimationPlayback>,
 }
 
 impl Spaceship {
@@ -79,9 +204,28 @@ impl Spaceship {
             orientation,
             velocity: Vector3::zero(),
             rotation: Vector3::zero(),
+            previous: Transform {
+                position,
+                orientation,
+            },
             state: SpaceshipState::Idle,
+            wander: WanderProps::new(2.0, 1.0, 4.0),
+            patrol_path: None,
+            look_where_you_are_going: true,
+            animation: None,
         }
     }
+
+    pub fn transform(&self) -> Transform {
+        Transform {
+            position: self.position,
+            orientation: self.orientation,
+        }
+    }
+
+    pub fn interpolated_transform(&self, alpha: f32) -> Transform {
+        self.previous.interpolate(&self.transform(), alpha)
+    }
 }
 
 impl Kinematic for Spaceship {
@@ -92,12 +236,19 @@ impl Kinematic for Spaceship {
             velocity: self.velocity,
             rotation: self.rotation,
             max_acceleration: 1.0,
+            max_speed: 0.5,
+            max_angular_acceleration: 10.0,
+            max_rotation: 1.0,
         }
     }
 
     fn update(&mut self, steering: SteeringOutput, dt: std::time::Duration) {
         let dt = dt.as_secs_f32();
-        let max_speed = 0.5;
+        let KinematicProps {
+            max_speed,
+            max_rotation,
+            ..
+        } = self.props();
 
         self.position += self.velocity * dt;
         self.orientation = self.orientation * delta_rotation(self.rotation, dt);
@@ -109,8 +260,11 @@ impl Kinematic for Spaceship {
             .map_or(Vector3::zero(), |angular| self.rotation + (angular * dt));
 
         if self.velocity.magnitude() > max_speed {
-            self.velocity.normalize();
-            self.velocity *= max_speed;
+            self.velocity = self.velocity.normalize() * max_speed;
+        }
+
+        if self.rotation.magnitude() > max_rotation {
+            self.rotation = self.rotation.normalize() * max_rotation;
         }
     }
 }
@@ -132,9 +286,48 @@ fn delta_rotation(rotation: Vector3<f32>, dt: f32) -> Quaternion<f32> {
     Quaternion::from_sv(scalar_part, vector_part)
 }
 
-fn distance_to_color_intensity(distance_from_origin: f32) -> f32 {
-    let max_intensity_distance = 5.0;
-    let ratio = distance_from_origin.abs() / max_intensity_distance;
+//
+// Tests
+//
+
+#[cfg(test)]
+mod tests {
+    use crate::entity::{AnimationPlayback, Transform};
+    use cgmath::{prelude::*, Quaternion, Rad, Vector3};
 
-    ratio.clamp(0.0, 1.0)
+    #[test]
+    fn test_transform_interpolation() {
+        let previous = Transform {
+            position: Vector3::new(0.0, 0.0, 0.0),
+            orientation: Quaternion::from_angle_y(Rad(0.0)),
+        };
+        let next = Transform {
+            position: Vector3::new(2.0, 0.0, 0.0),
+            // Stored with a negative scalar part, which must not make the blend go the long way
+            orientation: -Quaternion::from_angle_y(Rad(1.0)),
+        };
+        let halfway = previous.interpolate(&next, 0.5);
+
+        assert_eq!(halfway.position, Vector3::new(1.0, 0.0, 0.0));
+
+        let facing = halfway.orientation * Vector3::unit_z();
+        let expected = Quaternion::from_angle_y(Rad(0.5)) * Vector3::unit_z();
+        assert!((facing - expected).magnitude() < 0.0001);
+    }
+
+    #[test]
+    fn test_animation_playback() {
+        let mut playback = AnimationPlayback::new("wave", 2.0, true);
+
+        playback.advance(std::time::Duration::from_millis(500));
+
+        assert_eq!(playback.time, 1.0);
+        assert_eq!(playback.interpolated_time(0.25), 0.25);
+
+        playback.paused = true;
+        playback.advance(std::time::Duration::from_millis(500));
+
+        assert_eq!(playback.time, 1.0);
+        assert_eq!(playback.interpolated_time(0.5), 1.0);
+    }
 }
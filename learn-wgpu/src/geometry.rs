@@ -0,0 +1,573 @@
+use anyhow::{bail, Result};
+use cgmath::{prelude::*, Vector2, Vector3};
+use serde::{Deserialize, Serialize};
+
+use crate::model::SkinVertex;
+
+// How to make up normals for a mesh that has none
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NormalGeneration {
+    // One normal per triangle, for hard edges everywhere
+    Flat,
+    // Shared between the triangles around a vertex, weighted by their angle at it
+    Smooth,
+}
+
+// How to make up texture coordinates for a mesh that has none
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UvGeneration {
+    // Project everything onto the plane the mesh is largest in
+    Planar,
+    // Project each vertex along the axis its normal is closest to
+    Box,
+}
+
+// What a model falls back to when its file leaves vertex data out
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct MeshFallbacks {
+    pub normals: NormalGeneration,
+    pub uvs: UvGeneration,
+}
+
+impl Default for MeshFallbacks {
+    fn default() -> Self {
+        MeshFallbacks {
+            normals: NormalGeneration::Smooth,
+            uvs: UvGeneration::Box,
+        }
+    }
+}
+
+// Vertex data the way a model file has it, where normals and texture coordinates may be left out
+pub struct MeshData {
+    pub positions: Vec<[f32; 3]>,
+    pub tex_coords: Option<Vec<[f32; 2]>>,
+    pub normals: Option<Vec<[f32; 3]>>,
+    pub indices: Vec<u32>,
+    // For skinned models only
+    pub skin: Option<Vec<SkinVertex>>,
+}
+
+// Everything a vertex needs, one entry per vertex in each
+pub struct CompleteMesh {
+    pub positions: Vec<[f32; 3]>,
+    pub tex_coords: Vec<[f32; 2]>,
+    pub normals: Vec<[f32; 3]>,
+    pub tangents: Vec<[f32; 4]>,
+    pub indices: Vec<u32>,
+    pub skin: Option<Vec<SkinVertex>>,
+}
+
+impl MeshData {
+    // Make up whatever the file left out
+    pub fn complete(self, fallbacks: MeshFallbacks) -> CompleteMesh {
+        let MeshData {
+            mut positions,
+            mut tex_coords,
+            normals,
+            mut indices,
+            mut skin,
+        } = self;
+
+        let normals = match (normals, fallbacks.normals) {
+            (Some(normals), _) => normals,
+            (None, NormalGeneration::Flat) => {
+                // Every triangle needs vertices of its own to keep its normal
+                tex_coords = tex_coords.map(|tex_coords| unweld(&tex_coords, &indices).0);
+                skin = skin.map(|skin| unweld(&skin, &indices).0);
+                (positions, indices) = unweld(&positions, &indices);
+                flat_normals(&positions, &indices)
+            }
+            (None, NormalGeneration::Smooth) => smooth_normals(&positions, &indices),
+        };
+        let tex_coords = tex_coords.unwrap_or_else(|| match fallbacks.uvs {
+            UvGeneration::Planar => planar_uvs(&positions),
+            UvGeneration::Box => box_uvs(&positions, &normals),
+        });
+        let tangents = tangents(&positions, &tex_coords, &normals, &indices);
+
+        CompleteMesh {
+            positions,
+            tex_coords,
+            normals,
+            tangents,
+            indices,
+            skin,
+        }
+    }
+}
+
+// Check that flat position data and triangle indices fit together, and group the positions by vertex
+pub fn read_positions(positions: &[f32], indices: &[u32]) -> Result<Vec<[f32; 3]>> {
+    if !positions.len().is_multiple_of(3) {
+        bail!(
+            "{} position values don't make whole xyz positions",
+            positions.len()
+        );
+    }
+
+    if let Some(value) = positions.iter().find(|value| !value.is_finite()) {
+        bail!("a position has the value {}", value);
+    }
+
+    if !indices.len().is_multiple_of(3) {
+        bail!("{} indices don't make whole triangles", indices.len());
+    }
+
+    let vertex_count = positions.len() / 3;
+
+    if let Some(index) = indices
+        .iter()
+        .find(|index| **index as usize >= vertex_count)
+    {
+        bail!(
+            "index {} points past the last of {} vertices",
+            index,
+            vertex_count
+        );
+    }
+
+    Ok(read_attribute(positions, vertex_count, "position")?.unwrap_or_default())
+}
+
+// Group flat attribute data by vertex. None when the file has none of it at all
+pub fn read_attribute<const N: usize>(
+    values: &[f32],
+    vertex_count: usize,
+    name: &str,
+) -> Result<Option<Vec<[f32; N]>>> {
+    if values.is_empty() {
+        return Ok(None);
+    }
+
+    if values.len() != vertex_count * N {
+        bail!(
+            "there are {} {} values for {} vertices, instead of {} each",
+            values.len(),
+            name,
+            vertex_count,
+            N
+        );
+    }
+
+    Ok(Some(
+        values
+            .chunks_exact(N)
+            .map(|chunk| {
+                let mut vertex = [0.0; N];
+
+                vertex.copy_from_slice(chunk);
+                vertex
+            })
+            .collect(),
+    ))
+}
+
+// Give every triangle its own copy of its vertices, so that they can get a normal per triangle
+pub fn unweld<T: Copy>(vertices: &[T], indices: &[u32]) -> (Vec<T>, Vec<u32>) {
+    (
+        indices
+            .iter()
+            .map(|index| vertices[*index as usize])
+            .collect(),
+        (0..indices.len() as u32).collect(),
+    )
+}
+
+// The normal of each triangle on its vertices. Meant for unwelded vertices,
+// otherwise a vertex ends up with the normal of the last triangle using it
+pub fn flat_normals(positions: &[[f32; 3]], indices: &[u32]) -> Vec<[f32; 3]> {
+    let mut normals = vec![[0.0, 1.0, 0.0]; positions.len()];
+
+    for triangle in indices.chunks_exact(3) {
+        let normal = triangle_normal(positions, triangle).unwrap_or_else(Vector3::unit_y);
+
+        for index in triangle {
+            normals[*index as usize] = normal.into();
+        }
+    }
+
+    normals
+}
+
+// The average normal of the triangles around each vertex, weighted by how wide
+// each triangle's angle at the vertex is. Unlike weighting by area, this
+// doesn't change when a face is split into more triangles
+pub fn smooth_normals(positions: &[[f32; 3]], indices: &[u32]) -> Vec<[f32; 3]> {
+    let mut sums = vec![Vector3::zero(); positions.len()];
+
+    for triangle in indices.chunks_exact(3) {
+        let normal = match triangle_normal(positions, triangle) {
+            Some(normal) => normal,
+            // a degenerate triangle points nowhere
+            None => continue,
+        };
+
+        for corner in 0..3 {
+            let at = Vector3::from(positions[triangle[corner] as usize]);
+            let to_next = Vector3::from(positions[triangle[(corner + 1) % 3] as usize]) - at;
+            let to_previous = Vector3::from(positions[triangle[(corner + 2) % 3] as usize]) - at;
+
+            sums[triangle[corner] as usize] += normal * to_next.angle(to_previous).0;
+        }
+    }
+
+    sums.into_iter()
+        .map(|sum| {
+            if sum.magnitude2() > 0.0 {
+                sum.normalize().into()
+            } else {
+                [0.0, 1.0, 0.0]
+            }
+        })
+        .collect()
+}
+
+// Texture coordinates from the two axes the mesh extends furthest along
+pub fn planar_uvs(positions: &[[f32; 3]]) -> Vec<[f32; 2]> {
+    let (min, size) = bounds(positions);
+    let extent = size.iter().copied().fold(0.0, f32::max);
+    let mut axes = [0, 1, 2];
+
+    // the smallest side goes last, and is left out
+    axes.sort_by(|a, b| size[*b].total_cmp(&size[*a]));
+
+    positions
+        .iter()
+        .map(|position| project(position, min, extent, axes[0], axes[1]))
+        .collect()
+}
+
+// Texture coordinates from the two axes across each vertex's normal
+pub fn box_uvs(positions: &[[f32; 3]], normals: &[[f32; 3]]) -> Vec<[f32; 2]> {
+    let (min, size) = bounds(positions);
+    let extent = size.iter().copied().fold(0.0, f32::max);
+
+    positions
+        .iter()
+        .zip(normals.iter())
+        .map(|(position, normal)| {
+            let [x, y, z] = normal.map(f32::abs);
+
+            if x >= y && x >= z {
+                project(position, min, extent, 2, 1)
+            } else if y >= z {
+                project(position, min, extent, 0, 2)
+            } else {
+                project(position, min, extent, 0, 1)
+            }
+        })
+        .collect()
+}
+
+// A tangent per vertex with the sign of its bitangent in w, MikkTSpace style: each
+// triangle's tangent and bitangent are summed up weighted by its angle at the vertex,
+// then the tangent is made perpendicular to the vertex normal. Triangles whose texture
+// coordinates don't span an area have no tangent to give, and are skipped
+pub fn tangents(
+    positions: &[[f32; 3]],
+    tex_coords: &[[f32; 2]],
+    normals: &[[f32; 3]],
+    indices: &[u32],
+) -> Vec<[f32; 4]> {
+    let mut tangent_sums = vec![Vector3::zero(); positions.len()];
+    let mut bitangent_sums = vec![Vector3::zero(); positions.len()];
+
+    for triangle in indices.chunks_exact(3) {
+        if triangle_normal(positions, triangle).is_none() {
+            continue;
+        }
+
+        let [i0, i1, i2] = [0, 1, 2].map(|corner| triangle[corner] as usize);
+        let delta_pos1 = Vector3::from(positions[i1]) - Vector3::from(positions[i0]);
+        let delta_pos2 = Vector3::from(positions[i2]) - Vector3::from(positions[i0]);
+        let delta_uv1 = Vector2::from(tex_coords[i1]) - Vector2::from(tex_coords[i0]);
+        let delta_uv2 = Vector2::from(tex_coords[i2]) - Vector2::from(tex_coords[i0]);
+
+        // Solving
+        //     delta_pos1 = delta_uv1.x * T + delta_uv1.y * B
+        //     delta_pos2 = delta_uv2.x * T + delta_uv2.y * B
+        // only needs the sign of the determinant's inverse, since both get normalized
+        let determinant = delta_uv1.x * delta_uv2.y - delta_uv1.y * delta_uv2.x;
+
+        if determinant.abs() <= f32::EPSILON {
+            continue;
+        }
+
+        let tangent = (delta_pos1 * delta_uv2.y - delta_pos2 * delta_uv1.y) * determinant.signum();
+        let bitangent =
+            (delta_pos2 * delta_uv1.x - delta_pos1 * delta_uv2.x) * determinant.signum();
+
+        if tangent.magnitude2() == 0.0 || bitangent.magnitude2() == 0.0 {
+            continue;
+        }
+
+        let (tangent, bitangent) = (tangent.normalize(), bitangent.normalize());
+
+        for corner in 0..3 {
+            let at = Vector3::from(positions[triangle[corner] as usize]);
+            let to_next = Vector3::from(positions[triangle[(corner + 1) % 3] as usize]) - at;
+            let to_previous = Vector3::from(positions[triangle[(corner + 2) % 3] as usize]) - at;
+            let angle = to_next.angle(to_previous).0;
+
+            tangent_sums[triangle[corner] as usize] += tangent * angle;
+            bitangent_sums[triangle[corner] as usize] += bitangent * angle;
+        }
+    }
+
+    normals
+        .iter()
+        .zip(tangent_sums.into_iter().zip(bitangent_sums))
+        .map(|(normal, (tangent, bitangent))| {
+            let normal = Vector3::from(*normal);
+            let mut tangent = tangent - normal * normal.dot(tangent);
+
+            if tangent.magnitude2() <= f32::EPSILON {
+                // Nothing to go on, but the normal map still needs some frame around the normal
+                let axis = if normal.x.abs() < 0.9 {
+                    Vector3::unit_x()
+                } else {
+                    Vector3::unit_y()
+                };
+
+                tangent = axis - normal * normal.dot(axis);
+            }
+
+            let tangent = tangent.normalize();
+            let sign = if normal.cross(tangent).dot(bitangent) < 0.0 {
+                -1.0
+            } else {
+                1.0
+            };
+
+            [tangent.x, tangent.y, tangent.z, sign]
+        })
+        .collect()
+}
+
+fn triangle_normal(positions: &[[f32; 3]], triangle: &[u32]) -> Option<Vector3<f32>> {
+    let p0 = Vector3::from(positions[triangle[0] as usize]);
+    let p1 = Vector3::from(positions[triangle[1] as usize]);
+    let p2 = Vector3::from(positions[triangle[2] as usize]);
+    let normal = (p1 - p0).cross(p2 - p0);
+
+    if normal.magnitude2() > 0.0 {
+        Some(normal.normalize())
+    } else {
+        None
+    }
+}
+
+// The corner of the bounding box and its size along each axis
+fn bounds(positions: &[[f32; 3]]) -> ([f32; 3], [f32; 3]) {
+    let mut min = [f32::MAX; 3];
+    let mut max = [f32::MIN; 3];
+
+    for position in positions {
+        for axis in 0..3 {
+            min[axis] = min[axis].min(position[axis]);
+            max[axis] = max[axis].max(position[axis]);
+        }
+    }
+
+    (min, [max[0] - min[0], max[1] - min[1], max[2] - min[2]])
+}
+
+// Both axes are scaled by the largest side of the mesh, so that projections keep their proportions
+fn project(
+    position: &[f32; 3],
+    min: [f32; 3],
+    extent: f32,
+    u_axis: usize,
+    v_axis: usize,
+) -> [f32; 2] {
+    let extent = if extent > 0.0 { extent } else { 1.0 };
+
+    [
+        (position[u_axis] - min[u_axis]) / extent,
+        // texture rows run down, positions up
+        1.0 - (position[v_axis] - min[v_axis]) / extent,
+    ]
+}
+
+//
+// Tests
+//
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::geometry::{
+        box_uvs, flat_normals, planar_uvs, read_attribute, read_positions, smooth_normals,
+        tangents, unweld,
+    };
+
+    // Two triangles folded 90 degrees along the x axis, one flat on the floor and one upright
+    const FOLD: [[f32; 3]; 4] = [
+        [0.0, 0.0, 0.0],
+        [1.0, 0.0, 0.0],
+        [0.0, 0.0, -1.0],
+        [0.0, 1.0, 0.0],
+    ];
+    const FOLD_INDICES: [u32; 6] = [0, 1, 2, 0, 1, 3];
+
+    // Shared with the tests of the other modules that build geometry
+    pub(crate) fn assert_close(actual: impl Into<[f32; 3]>, expected: impl Into<[f32; 3]>) {
+        let (actual, expected) = (actual.into(), expected.into());
+
+        for axis in 0..3 {
+            assert!(
+                (actual[axis] - expected[axis]).abs() < 0.0001,
+                "{:?} != {:?}",
+                actual,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_malformed_positions() {
+        let error = |positions: &[f32], indices: &[u32]| {
+            read_positions(positions, indices).unwrap_err().to_string()
+        };
+
+        assert!(error(&[0.0; 8], &[]).contains("don't make whole xyz positions"));
+        assert!(error(&[0.0, f32::NAN, 0.0], &[]).contains("has the value NaN"));
+        assert!(error(&[0.0; 9], &[0, 1]).contains("don't make whole triangles"));
+        assert!(error(&[0.0; 9], &[0, 1, 3]).contains("index 3 points past the last of 3"));
+        assert!(read_attribute::<2>(&[0.0; 5], 3, "texture coordinate")
+            .unwrap_err()
+            .to_string()
+            .contains("5 texture coordinate values for 3 vertices"));
+        assert_eq!(
+            read_attribute::<2>(&[], 3, "texture coordinate").unwrap(),
+            None
+        );
+        assert_eq!(
+            read_positions(&[0.0; 9], &[0, 1, 2]).unwrap(),
+            vec![[0.0; 3]; 3]
+        );
+    }
+
+    #[test]
+    fn test_generated_normals() {
+        // Shared vertices on the fold get the average of both sides
+        let smooth = smooth_normals(&FOLD, &FOLD_INDICES);
+
+        assert_close(smooth[0], [0.0, 0.5f32.sqrt(), 0.5f32.sqrt()]);
+        assert_close(smooth[2], [0.0, 1.0, 0.0]);
+        assert_close(smooth[3], [0.0, 0.0, 1.0]);
+
+        // Unwelded, every triangle keeps its own
+        let (positions, indices) = unweld(&FOLD, &FOLD_INDICES);
+        let flat = flat_normals(&positions, &indices);
+
+        assert_eq!(positions.len(), 6);
+        assert_close(flat[0], [0.0, 1.0, 0.0]);
+        assert_close(flat[3], [0.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn test_angle_weighting() {
+        // A quad on the floor split into a wide and a narrow triangle at vertex 0,
+        // plus a wall with a 90 degree corner there. Splitting the floor differently
+        // doesn't change its share, since its angles at vertex 0 add up the same
+        let positions = [
+            [0.0, 0.0, 0.0],
+            [1.0, 0.0, 0.0],
+            [1.0, 0.0, -1.0],
+            [0.0, 0.0, -1.0],
+            [0.0, 1.0, 0.0],
+        ];
+        let split = smooth_normals(&positions, &[0, 1, 2, 0, 2, 3, 0, 4, 1]);
+        let whole = smooth_normals(&positions, &[0, 1, 3, 0, 4, 1]);
+
+        assert_close(split[0], whole[0]);
+    }
+
+    #[test]
+    fn test_generated_uvs() {
+        let planar = planar_uvs(&FOLD);
+        let boxed = box_uvs(&FOLD, &smooth_normals(&FOLD, &FOLD_INDICES));
+
+        for uv in planar.iter().chain(boxed.iter()) {
+            assert!((0.0..=1.0).contains(&uv[0]) && (0.0..=1.0).contains(&uv[1]));
+        }
+
+        // The upright vertex is projected along z, so its height shows as v
+        assert_eq!(boxed[3], [0.0, 0.0]);
+    }
+
+    // Corners of a square on the xy plane, facing +z
+    const SQUARE: [[f32; 3]; 4] = [
+        [0.0, 0.0, 0.0],
+        [1.0, 0.0, 0.0],
+        [1.0, 1.0, 0.0],
+        [0.0, 1.0, 0.0],
+    ];
+    const SQUARE_INDICES: [u32; 6] = [0, 1, 2, 0, 2, 3];
+    const UP: [[f32; 3]; 4] = [[0.0, 0.0, 1.0]; 4];
+
+    #[test]
+    fn test_tangent_handedness() {
+        let uvs = SQUARE.map(|[x, y, _]| [x, y]);
+        let mirrored = SQUARE.map(|[x, y, _]| [1.0 - x, y]);
+
+        for tangent in tangents(&SQUARE, &uvs, &UP, &SQUARE_INDICES) {
+            assert_eq!(tangent, [1.0, 0.0, 0.0, 1.0]);
+        }
+
+        // The bitangent still points along v, so it's on the other side of the tangent
+        for tangent in tangents(&SQUARE, &mirrored, &UP, &SQUARE_INDICES) {
+            assert_eq!(tangent, [-1.0, 0.0, 0.0, -1.0]);
+        }
+    }
+
+    #[test]
+    fn test_tangents_averaged() {
+        // Two triangles at right angles around vertex 0, textured along different directions
+        let positions = [
+            [0.0, 0.0, 0.0],
+            [1.0, 0.0, 0.0],
+            [0.0, 1.0, 0.0],
+            [-1.0, 0.0, 0.0],
+        ];
+        let uvs = [[0.0, 0.0], [1.0, 0.0], [0.0, 1.0], [-1.0, -1.0]];
+        let result = tangents(&positions, &uvs, &UP, &[0, 1, 2, 0, 2, 3]);
+        let [x, y, z, _] = result[1];
+        let [other_x, other_y, other_z, _] = result[3];
+        let half = (x + other_x, y + other_y, z + other_z);
+        let length = (half.0 * half.0 + half.1 * half.1 + half.2 * half.2).sqrt();
+
+        // Not just whichever triangle came last
+        assert_close([x, y, z], [1.0, 0.0, 0.0]);
+        assert_close(
+            [other_x, other_y, other_z],
+            [0.5f32.sqrt(), -(0.5f32.sqrt()), 0.0],
+        );
+        assert_close(
+            [result[0][0], result[0][1], result[0][2]],
+            [half.0 / length, half.1 / length, half.2 / length],
+        );
+    }
+
+    #[test]
+    fn test_tangents_orthonormal() {
+        // A normal leaning away from the face pulls the tangent out of the face's plane
+        let leaning = [[0.6, 0.8, 0.0]; 4];
+        let uvs = SQUARE.map(|[x, y, _]| [x, y]);
+        let result = tangents(&SQUARE, &uvs, &leaning, &SQUARE_INDICES);
+
+        assert_close([result[0][0], result[0][1], result[0][2]], [0.8, -0.6, 0.0]);
+
+        // Texture coordinates that collapse to a point give a tangent of some kind, not NaN
+        let collapsed = tangents(&SQUARE, &[[0.5, 0.5]; 4], &UP, &SQUARE_INDICES);
+
+        for [x, y, z, w] in collapsed {
+            assert_close([x, y, z], [1.0, 0.0, 0.0]);
+            assert_eq!(w, 1.0);
+        }
+    }
+}
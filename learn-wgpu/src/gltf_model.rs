@@ -0,0 +1,834 @@
+use anyhow::{bail, Context, Result};
+use cgmath::{prelude::*, Matrix3, Matrix4, Quaternion, Vector3};
+use image::{DynamicImage, ImageBuffer};
+use std::path::Path;
+
+use crate::animation::{Channel, Clip, Interpolation, Joint, JointPose, Keyframes, Skeleton};
+use crate::geometry::{read_positions, MeshData, MeshFallbacks};
+use crate::model::{
+    push_default_material, Material, MaterialUniform, Mesh, Model, SkinVertex, FALLBACK_DIFFUSE,
+    FALLBACK_NORMAL,
+};
+use crate::texture;
+
+// Load a .gltf or .glb file, along with the buffers and images it refers to, whether
+// they're embedded or files next to it. Every mesh is placed where its node is in the
+// scene, since Model has no hierarchy of its own
+pub fn load(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    layout: &wgpu::BindGroupLayout,
+    path: &Path,
+    fallbacks: MeshFallbacks,
+) -> Result<Model> {
+    let (document, buffers, images) =
+        gltf::import(path).with_context(|| format!("Could not load {}", path.display()))?;
+
+    let mut materials = document
+        .materials()
+        .map(|material| load_material(device, queue, layout, &material, &images))
+        .collect::<Result<Vec<_>>>()
+        .with_context(|| format!("Could not load the materials of {}", path.display()))?;
+
+    let default_material = push_default_material(&mut materials, device, queue, layout)?;
+
+    let instances = mesh_instances(&document);
+    // A Model has a single skeleton, so only the first skin in the scene is followed
+    let skin = instances.iter().find_map(|(_, node)| node.skin());
+    let skeleton = skin
+        .as_ref()
+        .map(|skin| load_skeleton(&document, skin, &buffers))
+        .transpose()
+        .with_context(|| format!("Could not load the skeleton of {}", path.display()))?;
+    let clips = match (&skin, &skeleton) {
+        (Some(skin), Some(skeleton)) => load_clips(&document, skin, skeleton, &buffers)
+            .with_context(|| format!("Could not load the animations of {}", path.display()))?,
+        _ => Vec::new(),
+    };
+
+    let mut meshes = Vec::new();
+
+    for (transform, node) in instances {
+        let mesh = match node.mesh() {
+            Some(mesh) => mesh,
+            None => continue,
+        };
+        let name = mesh.name().unwrap_or("mesh");
+        let node_skin = node.skin().map(|skin| skin.index());
+        let is_skinned = node_skin.is_some() && node_skin == skin.as_ref().map(|skin| skin.index());
+
+        if node_skin.is_some() && !is_skinned {
+            log::warn!(
+                "Drawing {:?} in {} without its skin, since only the first skin is supported",
+                name,
+                path.display()
+            );
+        }
+
+        for primitive in mesh.primitives() {
+            if primitive.mode() != gltf::mesh::Mode::Triangles {
+                log::warn!(
+                    "Skipping a primitive of {:?} in {}, which isn't made of triangles",
+                    name,
+                    path.display()
+                );
+                continue;
+            }
+
+            let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+            let malformed = || format!("Malformed mesh {:?} in {}", name, path.display());
+            let mut data = read_primitive(&reader).with_context(malformed)?;
+
+            match &skeleton {
+                // The joints place skinned vertices, so their node's transform doesn't apply
+                Some(skeleton) if is_skinned => {
+                    data.skin = Some(
+                        read_skin(&reader, data.positions.len(), skeleton.joint_count())
+                            .with_context(malformed)?,
+                    );
+                }
+                // Every mesh of a skinned model goes through the skinned pipeline,
+                // where no weights at all leave a vertex where it is
+                Some(_) => {
+                    transform_mesh(&mut data, transform);
+                    data.skin = Some(vec![SkinVertex::default(); data.positions.len()]);
+                }
+                None => transform_mesh(&mut data, transform),
+            }
+
+            let material = primitive.material().index().unwrap_or(default_material);
+
+            meshes.push(Mesh::new(device, name, data, material, fallbacks));
+        }
+    }
+
+    Ok(Model {
+        meshes,
+        materials,
+        skeleton,
+        clips,
+    })
+}
+
+// The nodes with a mesh, each with its transform relative to the scene. Files
+// without a scene get the nodes that aren't the child of any other
+fn mesh_instances(document: &gltf::Document) -> Vec<(Matrix4<f32>, gltf::Node<'_>)> {
+    let roots = match document
+        .default_scene()
+        .or_else(|| document.scenes().next())
+    {
+        Some(scene) => scene.nodes().collect::<Vec<_>>(),
+        None => {
+            let parents = node_parents(document);
+
+            document
+                .nodes()
+                .filter(|node| parents[node.index()].is_none())
+                .collect()
+        }
+    };
+
+    let mut instances = Vec::new();
+
+    for node in roots {
+        add_node(node, Matrix4::identity(), &mut instances);
+    }
+
+    instances
+}
+
+fn add_node<'a>(
+    node: gltf::Node<'a>,
+    parent: Matrix4<f32>,
+    instances: &mut Vec<(Matrix4<f32>, gltf::Node<'a>)>,
+) {
+    let transform = parent * Matrix4::from(node.transform().matrix());
+
+    if node.mesh().is_some() {
+        instances.push((transform, node.clone()));
+    }
+
+    for child in node.children() {
+        add_node(child, transform, instances);
+    }
+}
+
+// The index of every node's parent, by node index
+fn node_parents(document: &gltf::Document) -> Vec<Option<usize>> {
+    let mut parents = vec![None; document.nodes().len()];
+
+    for node in document.nodes() {
+        for child in node.children() {
+            parents[child.index()] = Some(node.index());
+        }
+    }
+
+    parents
+}
+
+// Where a node is relative to the scene, when nothing is animated
+fn rest_transform(
+    document: &gltf::Document,
+    parents: &[Option<usize>],
+    index: usize,
+) -> Matrix4<f32> {
+    let node = document.nodes().nth(index).unwrap();
+    let local = Matrix4::from(node.transform().matrix());
+
+    match parents[index] {
+        Some(parent) => rest_transform(document, parents, parent) * local,
+        None => local,
+    }
+}
+
+// The joints of a skin, with their parents among its other joints. A root joint
+// keeps the rest transform of the nodes above it as its origin
+fn load_skeleton(
+    document: &gltf::Document,
+    skin: &gltf::Skin,
+    buffers: &[gltf::buffer::Data],
+) -> Result<Skeleton> {
+    let parents = node_parents(document);
+    let joint_nodes = skin.joints().map(|node| node.index()).collect::<Vec<_>>();
+    let inverse_binds = match skin
+        .reader(|buffer| Some(&buffers[buffer.index()]))
+        .read_inverse_bind_matrices()
+    {
+        Some(matrices) => matrices.map(Matrix4::from).collect::<Vec<_>>(),
+        // the joints were bound where they are
+        None => vec![Matrix4::identity(); joint_nodes.len()],
+    };
+
+    if inverse_binds.len() != joint_nodes.len() {
+        bail!(
+            "there are {} inverse bind matrices for {} joints",
+            inverse_binds.len(),
+            joint_nodes.len()
+        );
+    }
+
+    let joints = skin
+        .joints()
+        .zip(inverse_binds)
+        .map(|(node, inverse_bind)| {
+            let parent_node = parents[node.index()];
+            let parent = parent_node
+                .and_then(|parent_node| joint_nodes.iter().position(|joint| *joint == parent_node));
+            let origin = match (parent, parent_node) {
+                (None, Some(parent_node)) => rest_transform(document, &parents, parent_node),
+                _ => Matrix4::identity(),
+            };
+            let (translation, [x, y, z, w], scale) = node.transform().decomposed();
+
+            Joint {
+                parent,
+                origin,
+                rest: JointPose {
+                    translation: translation.into(),
+                    rotation: Quaternion::new(w, x, y, z),
+                    scale: scale.into(),
+                },
+                inverse_bind,
+            }
+        })
+        .collect();
+
+    Skeleton::new(joints)
+}
+
+// The animations that move the skin's joints, as clips. Channels of other nodes are
+// left out, since the meshes of those are fixed in place when they're loaded
+fn load_clips(
+    document: &gltf::Document,
+    skin: &gltf::Skin,
+    skeleton: &Skeleton,
+    buffers: &[gltf::buffer::Data],
+) -> Result<Vec<Clip>> {
+    use gltf::animation::util::ReadOutputs;
+
+    let joint_nodes = skin.joints().map(|node| node.index()).collect::<Vec<_>>();
+    let mut clips = Vec::new();
+
+    for animation in document.animations() {
+        let name = animation.name().map_or_else(
+            || format!("animation {}", animation.index()),
+            str::to_string,
+        );
+        let mut channels = Vec::new();
+
+        for channel in animation.channels() {
+            let target = channel.target().node().index();
+            let joint = match joint_nodes.iter().position(|node| *node == target) {
+                Some(joint) => joint,
+                None => continue,
+            };
+            let interpolation = match channel.sampler().interpolation() {
+                gltf::animation::Interpolation::Step => Interpolation::Step,
+                gltf::animation::Interpolation::Linear => Interpolation::Linear,
+                gltf::animation::Interpolation::CubicSpline => Interpolation::CubicSpline,
+            };
+            let reader = channel.reader(|buffer| Some(&buffers[buffer.index()]));
+            let times = reader
+                .read_inputs()
+                .with_context(|| format!("clip {:?} has no keyframe times", name))?
+                .collect();
+            let keyframes = match reader
+                .read_outputs()
+                .with_context(|| format!("clip {:?} has no keyframe values", name))?
+            {
+                ReadOutputs::Translations(values) => {
+                    Keyframes::Translation(values.map(Vector3::from).collect())
+                }
+                ReadOutputs::Rotations(values) => Keyframes::Rotation(
+                    values
+                        .into_f32()
+                        .map(|[x, y, z, w]| Quaternion::new(w, x, y, z))
+                        .collect(),
+                ),
+                ReadOutputs::Scales(values) => {
+                    Keyframes::Scale(values.map(Vector3::from).collect())
+                }
+                // there are no morph targets to weigh
+                ReadOutputs::MorphTargetWeights(_) => continue,
+            };
+
+            channels.push(Channel {
+                joint,
+                interpolation,
+                times,
+                keyframes,
+            });
+        }
+
+        if channels.is_empty() {
+            continue;
+        }
+
+        clips.push(
+            Clip::new(&name, channels, skeleton.joint_count())
+                .with_context(|| format!("Malformed clip {:?}", name))?,
+        );
+    }
+
+    Ok(clips)
+}
+
+fn read_primitive<'a, 's, F>(reader: &gltf::mesh::Reader<'a, 's, F>) -> Result<MeshData>
+where
+    F: Clone + Fn(gltf::Buffer<'a>) -> Option<&'s [u8]>,
+{
+    let positions = reader
+        .read_positions()
+        .context("there are no positions")?
+        .collect::<Vec<_>>();
+    let indices: Vec<u32> = match reader.read_indices() {
+        Some(indices) => indices.into_u32().collect(),
+        // every three vertices make a triangle
+        None => (0..positions.len() as u32).collect(),
+    };
+    let positions = read_positions(&positions.concat(), &indices)?;
+    let tex_coords = reader
+        .read_tex_coords(0)
+        .map(|tex_coords| tex_coords.into_f32().collect());
+    let normals = reader.read_normals().map(|normals| normals.collect());
+
+    Ok(MeshData {
+        tex_coords: check_count(tex_coords, positions.len(), "texture coordinates")?,
+        normals: check_count(normals, positions.len(), "normals")?,
+        positions,
+        indices,
+        skin: None,
+    })
+}
+
+fn read_skin<'a, 's, F>(
+    reader: &gltf::mesh::Reader<'a, 's, F>,
+    vertex_count: usize,
+    joint_count: usize,
+) -> Result<Vec<SkinVertex>>
+where
+    F: Clone + Fn(gltf::Buffer<'a>) -> Option<&'s [u8]>,
+{
+    let joints = reader
+        .read_joints(0)
+        .map(|joints| joints.into_u16().collect());
+    let weights = reader
+        .read_weights(0)
+        .map(|weights| weights.into_f32().collect());
+
+    match (
+        check_count(joints, vertex_count, "joint sets")?,
+        check_count(weights, vertex_count, "weight sets")?,
+    ) {
+        (Some(joints), Some(weights)) => skin_vertices(&joints, &weights, joint_count),
+        // a skinned node whose primitive isn't weighted to any joint stays put
+        _ => Ok(vec![SkinVertex::default(); vertex_count]),
+    }
+}
+
+// Pair up each vertex's joints and weights, with the weights scaled to add up to 1
+fn skin_vertices(
+    joints: &[[u16; 4]],
+    weights: &[[f32; 4]],
+    joint_count: usize,
+) -> Result<Vec<SkinVertex>> {
+    joints
+        .iter()
+        .zip(weights.iter())
+        .map(|(joints, weights)| {
+            let total: f32 = weights.iter().sum();
+
+            for (joint, weight) in joints.iter().zip(weights.iter()) {
+                if *weight != 0.0 && *joint as usize >= joint_count {
+                    bail!("joint {} is past the last of {} joints", joint, joint_count);
+                }
+            }
+
+            Ok(SkinVertex {
+                joints: joints.map(|joint| joint as u32),
+                weights: if total > 0.0 {
+                    weights.map(|weight| weight / total)
+                } else {
+                    [0.0; 4]
+                },
+            })
+        })
+        .collect()
+}
+
+fn check_count<T>(
+    values: Option<Vec<T>>,
+    vertex_count: usize,
+    name: &str,
+) -> Result<Option<Vec<T>>> {
+    match values {
+        Some(values) if values.len() != vertex_count => bail!(
+            "there are {} {} for {} vertices",
+            values.len(),
+            name,
+            vertex_count
+        ),
+        values => Ok(values),
+    }
+}
+
+// Move a mesh from its node's space into the scene's
+fn transform_mesh(data: &mut MeshData, transform: Matrix4<f32>) {
+    for position in &mut data.positions {
+        *position = transform
+            .transform_point(cgmath::Point3::from(*position))
+            .into();
+    }
+
+    let linear = Matrix3::from_cols(
+        transform.x.truncate(),
+        transform.y.truncate(),
+        transform.z.truncate(),
+    );
+
+    // Normals need the inverse transpose to stay perpendicular under uneven scaling
+    if let (Some(normals), Some(inverse)) = (&mut data.normals, linear.invert()) {
+        let normal_matrix = inverse.transpose();
+
+        for normal in normals.iter_mut() {
+            let transformed = normal_matrix * Vector3::from(*normal);
+
+            if transformed.magnitude2() > 0.0 {
+                *normal = transformed.normalize().into();
+            }
+        }
+    }
+
+    // A mirroring transform turns triangles inside out, unless they're wound the other way
+    if linear.determinant() < 0.0 {
+        for triangle in data.indices.chunks_exact_mut(3) {
+            triangle.swap(1, 2);
+        }
+    }
+}
+
+fn load_material(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    layout: &wgpu::BindGroupLayout,
+    material: &gltf::Material,
+    images: &[gltf::image::Data],
+) -> Result<Material> {
+    let name = material.name().unwrap_or("material");
+    let pbr = material.pbr_metallic_roughness();
+    let uniform = MaterialUniform::from_pbr(
+        pbr.base_color_factor(),
+        pbr.metallic_factor(),
+        pbr.roughness_factor(),
+        material.emissive_factor(),
+    );
+    // The shader has nowhere to put metallic-roughness, occlusion or emissive textures,
+    // so only the factors of those make it in
+    let load_texture = |texture: Option<gltf::Texture>,
+                        fallback_color,
+                        is_normal_map|
+     -> Result<texture::Texture> {
+        match texture {
+            Some(texture) => {
+                let image = to_image(&images[texture.source().index()])?;
+
+                texture::Texture::from_image(device, queue, &image, Some(name), is_normal_map)
+            }
+            None => texture::Texture::from_color(
+                device,
+                queue,
+                fallback_color,
+                "fallback_texture",
+                is_normal_map,
+            ),
+        }
+    };
+
+    let diffuse_texture = load_texture(
+        pbr.base_color_texture().map(|info| info.texture()),
+        FALLBACK_DIFFUSE,
+        false,
+    )?;
+    let normal_texture = load_texture(
+        material.normal_texture().map(|info| info.texture()),
+        FALLBACK_NORMAL,
+        true,
+    )?;
+
+    Ok(Material::new(
+        device,
+        name,
+        diffuse_texture,
+        normal_texture,
+        uniform,
+        layout,
+    ))
+}
+
+// gltf decodes images into raw pixels, in whichever format the file had them
+fn to_image(data: &gltf::image::Data) -> Result<DynamicImage> {
+    use gltf::image::Format;
+
+    let (width, height) = (data.width, data.height);
+    let pixels = data.pixels.clone();
+    // 16 bit channels come as pairs of bytes in native order
+    let wide = || {
+        data.pixels
+            .chunks_exact(2)
+            .map(|pair| u16::from_ne_bytes([pair[0], pair[1]]))
+            .collect::<Vec<_>>()
+    };
+
+    let image = match data.format {
+        Format::R8 => ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageLuma8),
+        Format::R8G8 => ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageLumaA8),
+        Format::R8G8B8 => ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageRgb8),
+        Format::R8G8B8A8 => {
+            ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageRgba8)
+        }
+        Format::B8G8R8 => ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageBgr8),
+        Format::B8G8R8A8 => {
+            ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageBgra8)
+        }
+        Format::R16 => ImageBuffer::from_raw(width, height, wide()).map(DynamicImage::ImageLuma16),
+        Format::R16G16 => {
+            ImageBuffer::from_raw(width, height, wide()).map(DynamicImage::ImageLumaA16)
+        }
+        Format::R16G16B16 => {
+            ImageBuffer::from_raw(width, height, wide()).map(DynamicImage::ImageRgb16)
+        }
+        Format::R16G16B16A16 => {
+            ImageBuffer::from_raw(width, height, wide()).map(DynamicImage::ImageRgba16)
+        }
+    };
+
+    image.with_context(|| {
+        format!(
+            "{} bytes of {:?} pixels don't make a {}x{} image",
+            data.pixels.len(),
+            data.format,
+            width,
+            height
+        )
+    })
+}
+
+//
+// Tests
+//
+
+#[cfg(test)]
+mod tests {
+    use crate::geometry::tests::assert_close;
+    use crate::geometry::MeshData;
+    use crate::gltf_model::{
+        load_clips, load_skeleton, mesh_instances, read_primitive, skin_vertices, to_image,
+        transform_mesh,
+    };
+    use cgmath::{Matrix4, SquareMatrix, Vector3};
+    use image::GenericImageView;
+
+    // A parent moved up and scaled, with a child moved along x inside it. The second
+    // scene is there to check that the default one is used
+    const HIERARCHY: &str = r#"{
+        "asset": {"version": "2.0"},
+        "scene": 0,
+        "scenes": [{"nodes": [0]}, {"nodes": [2]}],
+        "nodes": [
+            {"translation": [0, 1, 0], "scale": [2, 2, 2], "children": [1], "mesh": 0},
+            {"translation": [1, 0, 0], "mesh": 1},
+            {"mesh": 1}
+        ],
+        "meshes": [
+            {"name": "parent", "primitives": [{"attributes": {"POSITION": 0}}]},
+            {"name": "child", "primitives": [{"attributes": {"POSITION": 0}}]}
+        ],
+        "accessors": [{
+            "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3",
+            "min": [0, 0, 0], "max": [1, 1, 0]
+        }],
+        "bufferViews": [{"buffer": 0, "byteLength": 36}],
+        "buffers": [{"byteLength": 36}]
+    }"#;
+
+    fn origin(transform: Matrix4<f32>) -> Vector3<f32> {
+        transform.w.truncate()
+    }
+
+    #[test]
+    fn test_node_hierarchy() {
+        let gltf = gltf::Gltf::from_slice(HIERARCHY.as_bytes()).unwrap();
+        let instances = mesh_instances(&gltf.document);
+
+        assert_eq!(instances.len(), 2);
+        assert_eq!(instances[0].1.mesh().unwrap().name(), Some("parent"));
+        assert_close(origin(instances[0].0), [0.0, 1.0, 0.0]);
+        // The child's offset is scaled along with the rest of its parent
+        assert_eq!(instances[1].1.mesh().unwrap().name(), Some("child"));
+        assert_close(origin(instances[1].0), [2.0, 1.0, 0.0]);
+        assert_eq!(instances[1].0.x.x, 2.0);
+    }
+
+    #[test]
+    fn test_transform_mesh() {
+        let triangle = || MeshData {
+            positions: vec![[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]],
+            tex_coords: None,
+            normals: Some(vec![[0.0, 0.0, 1.0]; 3]),
+            indices: vec![0, 1, 2],
+            skin: None,
+        };
+
+        // Squashing along z leaves the normal pointing along z, but unit length
+        let mut squashed = triangle();
+
+        transform_mesh(
+            &mut squashed,
+            Matrix4::from_translation(Vector3::new(0.0, 0.0, 5.0))
+                * Matrix4::from_nonuniform_scale(3.0, 1.0, 0.5),
+        );
+
+        assert_close(squashed.positions[1], [3.0, 0.0, 5.0]);
+        assert_close(squashed.normals.unwrap()[0], [0.0, 0.0, 1.0]);
+        assert_eq!(squashed.indices, vec![0, 1, 2]);
+
+        // Mirrored, the triangle is wound the other way round to keep facing out
+        let mut mirrored = triangle();
+
+        transform_mesh(
+            &mut mirrored,
+            Matrix4::from_nonuniform_scale(-1.0, 1.0, 1.0),
+        );
+
+        assert_close(mirrored.positions[1], [-1.0, 0.0, 0.0]);
+        assert_eq!(mirrored.indices, vec![0, 2, 1]);
+
+        let mut unchanged = triangle();
+
+        transform_mesh(&mut unchanged, Matrix4::identity());
+
+        assert_eq!(unchanged.positions, triangle().positions);
+    }
+
+    #[test]
+    fn test_image_formats() {
+        let rgb = gltf::image::Data {
+            pixels: vec![255, 0, 0, 0, 255, 0],
+            format: gltf::image::Format::R8G8B8,
+            width: 2,
+            height: 1,
+        };
+        let image = to_image(&rgb).unwrap();
+
+        assert_eq!(image.dimensions(), (2, 1));
+        assert_eq!(
+            image.to_rgba8().into_raw(),
+            vec![255, 0, 0, 255, 0, 255, 0, 255]
+        );
+
+        let wide = gltf::image::Data {
+            pixels: [u16::MAX.to_ne_bytes(), 0u16.to_ne_bytes()].concat(),
+            format: gltf::image::Format::R16,
+            width: 2,
+            height: 1,
+        };
+
+        assert_eq!(
+            to_image(&wide).unwrap().to_rgba8().into_raw(),
+            vec![255, 255, 255, 255, 0, 0, 0, 255]
+        );
+
+        let short = gltf::image::Data {
+            pixels: vec![0; 5],
+            format: gltf::image::Format::R8G8B8,
+            width: 2,
+            height: 1,
+        };
+
+        assert!(to_image(&short)
+            .unwrap_err()
+            .to_string()
+            .contains("don't make a 2x1 image"));
+    }
+
+    #[test]
+    fn test_read_primitive() {
+        // One triangle without indices, once with texture coordinates for all of its
+        // vertices and once with too few
+        let file = r#"{
+            "asset": {"version": "2.0"},
+            "meshes": [{"primitives": [
+                {"attributes": {"POSITION": 0, "TEXCOORD_0": 1}},
+                {"attributes": {"POSITION": 0, "TEXCOORD_0": 2}}
+            ]}],
+            "accessors": [
+                {
+                    "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3",
+                    "min": [0, 0, 0], "max": [1, 1, 0]
+                },
+                {"bufferView": 1, "componentType": 5126, "count": 3, "type": "VEC2"},
+                {"bufferView": 1, "componentType": 5126, "count": 2, "type": "VEC2"}
+            ],
+            "bufferViews": [
+                {"buffer": 0, "byteLength": 36},
+                {"buffer": 0, "byteOffset": 36, "byteLength": 24}
+            ],
+            "buffers": [{
+                "byteLength": 60,
+                "uri": "data:application/octet-stream;base64,AAAAAAAAAAAAAAAAAACAPwAAAAAAAAAAAAAAAAAAgD8AAAAAAAAAAAAAAAAAAIA/AAAAAAAAAAAAAIA/"
+            }]
+        }"#;
+        let (document, buffers, _) = gltf::import_slice(file.as_bytes()).unwrap();
+        let primitives = document
+            .meshes()
+            .next()
+            .unwrap()
+            .primitives()
+            .collect::<Vec<_>>();
+        let read = |index: usize| {
+            read_primitive(&primitives[index].reader(|buffer| Some(&buffers[buffer.index()])))
+        };
+
+        let data = read(0).unwrap();
+
+        assert_eq!(data.positions[1], [1.0, 0.0, 0.0]);
+        assert_eq!(data.indices, vec![0, 1, 2]);
+        assert_eq!(data.tex_coords.unwrap()[2], [0.0, 1.0]);
+        assert!(data.normals.is_none());
+
+        let error = read(1).err().unwrap().to_string();
+
+        assert!(
+            error.contains("2 texture coordinates for 3 vertices"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn test_skin_vertices() {
+        let vertices = skin_vertices(
+            &[[0, 1, 0, 0], [2, 0, 7, 0]],
+            &[[3.0, 1.0, 0.0, 0.0], [0.0, 0.0, 0.0, 0.0]],
+            3,
+        )
+        .unwrap();
+
+        assert_eq!(vertices[0].joints, [0, 1, 0, 0]);
+        assert_eq!(vertices[0].weights, [0.75, 0.25, 0.0, 0.0]);
+        // Unweighted joints may point anywhere, and leave the vertex where it is
+        assert_eq!(vertices[1].weights, [0.0; 4]);
+
+        let error = skin_vertices(&[[0, 3, 0, 0]], &[[0.5, 0.5, 0.0, 0.0]], 3)
+            .err()
+            .unwrap()
+            .to_string();
+
+        assert!(
+            error.contains("joint 3 is past the last of 3 joints"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn test_load_skeleton() {
+        // A leg of two joints under an armature moved along z. The knee is raised
+        // over a second by the first animation, and the second only moves the
+        // armature, which isn't a joint
+        let file = r#"{
+            "asset": {"version": "2.0"},
+            "nodes": [
+                {"translation": [0, 0, 5], "children": [1]},
+                {"translation": [0, 1, 0], "children": [2]},
+                {"translation": [0, 1, 0]}
+            ],
+            "skins": [{"joints": [1, 2]}],
+            "animations": [
+                {
+                    "name": "walk",
+                    "channels": [{"sampler": 0, "target": {"node": 2, "path": "translation"}}],
+                    "samplers": [{"input": 0, "output": 1}]
+                },
+                {
+                    "channels": [{"sampler": 0, "target": {"node": 0, "path": "translation"}}],
+                    "samplers": [{"input": 0, "output": 1}]
+                }
+            ],
+            "accessors": [
+                {
+                    "bufferView": 0, "componentType": 5126, "count": 2, "type": "SCALAR",
+                    "min": [0], "max": [1]
+                },
+                {"bufferView": 1, "componentType": 5126, "count": 2, "type": "VEC3"}
+            ],
+            "bufferViews": [
+                {"buffer": 0, "byteLength": 8},
+                {"buffer": 0, "byteOffset": 8, "byteLength": 24}
+            ],
+            "buffers": [{
+                "byteLength": 32,
+                "uri": "data:application/octet-stream;base64,AAAAAAAAgD8AAAAAAACAPwAAAAAAAAAAAAAAQAAAAAA="
+            }]
+        }"#;
+        let (document, buffers, _) = gltf::import_slice(file.as_bytes()).unwrap();
+        let skin = document.skins().next().unwrap();
+        let skeleton = load_skeleton(&document, &skin, &buffers).unwrap();
+        let clips = load_clips(&document, &skin, &skeleton, &buffers).unwrap();
+
+        assert_eq!(skeleton.joint_count(), 2);
+        assert_eq!(clips.len(), 1);
+        assert_eq!(clips[0].name, "walk");
+        assert_eq!(clips[0].duration, 1.0);
+
+        // Without inverse bind matrices the palette is where each joint ends up
+        let rest = skeleton.animate(None, 0.0);
+
+        assert_close(origin(rest[0]), [0.0, 1.0, 5.0]);
+        assert_close(origin(rest[1]), [0.0, 2.0, 5.0]);
+
+        let raised = skeleton.animate(Some(&clips[0]), 1.0);
+
+        assert_close(origin(raised[1]), [0.0, 3.0, 5.0]);
+    }
+}
@@ -0,0 +1,160 @@
+use serde::Serialize;
+use std::io::Write;
+use std::time::Duration;
+
+use crate::world::World;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Json,
+    Csv,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "json" => Ok(OutputFormat::Json),
+            "csv" => Ok(OutputFormat::Csv),
+            _ => Err(anyhow::anyhow!(
+                "Unknown output format {:?}, expected json or csv",
+                value
+            )),
+        }
+    }
+}
+
+// Where one entity was after a tick
+#[derive(Debug, Serialize)]
+pub struct TrajectorySample {
+    pub tick: u64,
+    pub entity: &'static str,
+    // A light's id is its index
+    pub id: u16,
+    pub position: [f32; 3],
+    pub orientation: [f32; 4],
+    pub velocity: [f32; 3],
+}
+
+// Step the world without a window and write every entity's trajectory, starting from the initial state
+pub fn run(
+    world: &mut World,
+    ticks: u64,
+    dt: Duration,
+    format: OutputFormat,
+    output: &mut impl Write,
+) -> anyhow::Result<()> {
+    let mut samples = sample(world);
+
+    for _ in 0..ticks {
+        world.step(dt);
+        samples.extend(sample(world));
+    }
+
+    match format {
+        OutputFormat::Json => {
+            serde_json::to_writer_pretty(&mut *output, &samples)?;
+            writeln!(output)?;
+        }
+        OutputFormat::Csv => write_csv(&samples, output)?,
+    }
+
+    Ok(())
+}
+
+fn sample(world: &World) -> Vec<TrajectorySample> {
+    let light_samples = world
+        .lights()
+        .iter()
+        .enumerate()
+        .map(|(index, light)| TrajectorySample {
+            tick: world.tick(),
+            entity: "light",
+            id: index as u16,
+            position: light.position.into(),
+            orientation: [1.0, 0.0, 0.0, 0.0],
+            velocity: light.velocity.into(),
+        });
+
+    light_samples
+        .chain(world.spaceships().map(|spaceship| {
+            let orientation = spaceship.orientation;
+
+            TrajectorySample {
+                tick: world.tick(),
+                entity: "spaceship",
+                id: spaceship.id,
+                position: spaceship.position.into(),
+                orientation: [
+                    orientation.s,
+                    orientation.v.x,
+                    orientation.v.y,
+                    orientation.v.z,
+                ],
+                velocity: spaceship.velocity.into(),
+            }
+        }))
+        .collect()
+}
+
+fn write_csv(samples: &[TrajectorySample], output: &mut impl Write) -> std::io::Result<()> {
+    writeln!(
+        output,
+        "tick,entity,id,x,y,z,orientation_w,orientation_x,orientation_y,orientation_z,velocity_x,velocity_y,velocity_z"
+    )?;
+
+    for sample in samples {
+        let [x, y, z] = sample.position;
+        let [w, i, j, k] = sample.orientation;
+        let [vx, vy, vz] = sample.velocity;
+
+        writeln!(
+            output,
+            "{},{},{},{},{},{},{},{},{},{},{},{},{}",
+            sample.tick, sample.entity, sample.id, x, y, z, w, i, j, k, vx, vy, vz
+        )?;
+    }
+
+    Ok(())
+}
+
+//
+// Tests
+//
+
+#[cfg(test)]
+mod tests {
+    use crate::headless::{run, OutputFormat};
+    use crate::world::World;
+    use cgmath::{Quaternion, Vector3};
+    use std::time::Duration;
+
+    #[test]
+    fn test_csv_trajectories() {
+        let mut world = World::new(1);
+        world.add_spaceship(
+            1,
+            Vector3::new(3.0, 0.0, 0.0),
+            Quaternion::new(1.0, 0.0, 0.0, 0.0),
+        );
+
+        let mut output = Vec::new();
+        run(
+            &mut world,
+            10,
+            Duration::from_millis(16),
+            OutputFormat::Csv,
+            &mut output,
+        )
+        .unwrap();
+
+        let csv = String::from_utf8(output).unwrap();
+        let lines = csv.lines().collect::<Vec<_>>();
+
+        // A header, then the light and one ship for the initial state and each tick
+        assert_eq!(lines.len(), 1 + 2 * 11);
+        assert!(lines[1].starts_with("0,light,0,"));
+        assert!(lines[22].starts_with("10,spaceship,1,"));
+    }
+}
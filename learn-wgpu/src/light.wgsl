@@ -8,13 +8,28 @@ struct Uniforms {
 [[group(0), binding(0)]]
 var<uniform> uniforms: Uniforms;
 
-[[block]]
+// Matching the LIGHT_KIND constants in state.rs
+let LIGHT_KIND_POINT: u32 = 0u;
+let LIGHT_KIND_DIRECTIONAL: u32 = 1u;
+let LIGHT_KIND_SPOT: u32 = 2u;
+
 struct Light {
     position: vec3<f32>;
+    kind: u32;
     color: vec3<f32>;
+    range: f32;
+    direction: vec3<f32>;
+    inner_cone_cos: f32;
+    outer_cone_cos: f32;
+    casts_shadow: u32;
+};
+[[block]]
+struct Lights {
+    count: u32;
+    lights: [[stride(64)]] array<Light, 16>;
 };
 [[group(1), binding(0)]]
-var<uniform> light: Light;
+var<uniform> lights: Lights;
 
 struct VertexInput {
     [[location(0)]] position: vec3<f32>;
@@ -28,8 +43,16 @@ struct VertexOutput {
 [[stage(vertex)]]
 fn main(
     model: VertexInput,
+    // one marker cube per light
+    [[builtin(instance_index)]] instance_index: u32,
 ) -> VertexOutput {
-    let scale = 0.25;
+    let light = lights.lights[instance_index];
+    var scale: f32 = 0.25;
+
+    // a directional light is infinitely far away, so it has no marker
+    if (light.kind == LIGHT_KIND_DIRECTIONAL) {
+        scale = 0.0;
+    }
     var out: VertexOutput;
 
     out.clip_position = uniforms.view_proj * vec4<f32>(model.position * scale + light.position, 1.0);
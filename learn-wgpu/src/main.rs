@@ -1,11 +1,26 @@
+mod animation;
 mod camera;
 mod entity;
+mod geometry;
+mod gltf_model;
+mod headless;
 mod model;
+mod obstacle;
+mod path;
+mod recording;
+mod scene;
+mod shadow;
+mod snapshot;
+mod spatial;
 mod state;
 mod steering;
 mod texture;
+mod timestep;
+mod world;
 
-use cgmath::prelude::*;
+use recording::{Recorder, Recording, Replay};
+use scene::{Scene, SceneView};
+use snapshot::Snapshot;
 use state::State;
 use winit::{
     event::*,
@@ -13,9 +28,172 @@ use winit::{
     window::Fullscreen,
     window::WindowBuilder,
 };
+use world::World;
+
+// The value following a command line flag, e.g. `--tick-rate 120`
+fn arg_value<T>(flag: &str) -> Option<T>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    let args = std::env::args().collect::<Vec<_>>();
+
+    args.iter()
+        .position(|arg| arg == flag)
+        .and_then(|index| args.get(index + 1))
+        .map(|value| {
+            value
+                .parse()
+                .unwrap_or_else(|error| panic!("Invalid value for {}: {}", flag, error))
+        })
+}
+
+fn has_flag(flag: &str) -> bool {
+    std::env::args().any(|arg| arg == flag)
+}
+
+// `learn-wgpu headless [--ticks N] [--format json|csv] [--output PATH]` simulates
+// without a window and writes the trajectories to the output file or stdout
+fn run_headless(world: &mut World, tick_rate: u32) -> anyhow::Result<()> {
+    let ticks = arg_value("--ticks").unwrap_or(600);
+    let format = arg_value("--format").unwrap_or(headless::OutputFormat::Json);
+    let dt = timestep::FixedTimestep::new(tick_rate).tick();
+
+    match arg_value::<String>("--output") {
+        Some(path) => {
+            let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+
+            headless::run(world, ticks, dt, format, &mut file)
+        }
+        None => headless::run(world, ticks, dt, format, &mut std::io::stdout().lock()),
+    }
+}
+
+// `--snapshot PATH` resumes a saved snapshot, otherwise `--scene PATH` picks
+// the scenario to start from. The default one lives in res/scenes. The seed
+// is only returned for a scene, as a snapshot carries its own random state
+fn load_start(seed: Option<u64>) -> anyhow::Result<(World, SceneView, Option<u64>)> {
+    if let Some(path) = arg_value::<String>("--snapshot") {
+        let snapshot = Snapshot::load(path)?;
+
+        return Ok((snapshot.world, snapshot.view, None));
+    }
+
+    let scene_path = arg_value("--scene").unwrap_or_else(scene::default_scene_path);
+    let scene = Scene::load(&scene_path)?;
+    let seed = seed.unwrap_or_else(rand::random::<u64>);
+
+    // stderr, so that it doesn't end up in headless output
+    eprintln!("Simulation seed: {}", seed);
+
+    Ok((scene.build_world(seed), scene.view(), Some(seed)))
+}
+
+// `learn-wgpu replay PATH [--headless]` runs a recording again and checks
+// that the world ends up in the recorded state
+fn run_replay(path: &str) -> anyhow::Result<()> {
+    let recording = Recording::load(path)?;
+
+    match recording.seed {
+        Some(seed) => eprintln!("Replaying seed {} for {} ticks", seed, recording.final_tick),
+        None => eprintln!(
+            "Replaying a resumed snapshot for {} ticks",
+            recording.final_tick
+        ),
+    }
+
+    if !has_flag("--headless") {
+        run_window(
+            recording.initial_world.clone(),
+            &recording.view,
+            recording.tick_rate,
+            None,
+            Some(Replay::new(&recording)),
+        );
+    }
+
+    let world = recording.replay_headless();
+
+    if recording.verify(&world) {
+        println!("Replay matches the recording");
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(
+            "Replay state hash {:016x} differs from the recorded {:016x}",
+            world.state_hash(),
+            recording.final_hash
+        ))
+    }
+}
 
 fn main() {
     env_logger::init();
+
+    if std::env::args().nth(1).as_deref() == Some("replay") {
+        let path = std::env::args()
+            .nth(2)
+            .expect("Usage: learn-wgpu replay PATH [--headless]");
+
+        if let Err(error) = run_replay(&path) {
+            eprintln!("Replay failed: {:#}", error);
+            std::process::exit(1);
+        }
+
+        return;
+    }
+
+    // Simulation ticks per second
+    let tick_rate = arg_value("--tick-rate").unwrap_or(timestep::DEFAULT_TICK_RATE);
+    // Pass the seed of a previous run to `--seed` to reproduce it
+    let seed_arg = arg_value::<u64>("--seed");
+
+    // a snapshot carries on with the random state it was saved with
+    if seed_arg.is_some() && has_flag("--snapshot") {
+        eprintln!("--seed can't be combined with --snapshot, which resumes its own random state");
+        std::process::exit(1);
+    }
+
+    let (mut world, view, seed) = match load_start(seed_arg) {
+        Ok(start) => start,
+        Err(error) => {
+            eprintln!("{:#}", error);
+            std::process::exit(1);
+        }
+    };
+
+    if std::env::args().nth(1).as_deref() == Some("headless") {
+        if let Err(error) = run_headless(&mut world, tick_rate) {
+            eprintln!("Headless run failed: {:#}", error);
+            std::process::exit(1);
+        }
+
+        return;
+    }
+
+    // `--record PATH` writes the session to a file when the window closes
+    let recorder = arg_value::<String>("--record")
+        .map(|path| (path, Recorder::new(seed, tick_rate, &world, view.clone())));
+
+    run_window(world, &view, tick_rate, recorder, None);
+}
+
+// Written to the working directory, named after the tick it was taken on
+fn save_snapshot(state: &State) {
+    let path = format!("snapshot-{}.json", state.world().tick());
+
+    match state.snapshot().save(&path) {
+        Ok(_) => println!("Saved a snapshot to {}", path),
+        Err(error) => eprintln!("{:#}", error),
+    }
+}
+
+fn run_window(
+    world: World,
+    view: &SceneView,
+    tick_rate: u32,
+    mut recorder: Option<(String, Recorder)>,
+    replay: Option<Replay>,
+) -> ! {
     let event_loop = EventLoop::new();
 
     // TODO: chain Option values all the way to Option<Fullscreen>
@@ -27,74 +205,22 @@ fn main() {
         .unwrap();
 
     // Since main can't be async, we're going to need to block
-    let mut state = pollster::block_on(State::new(&window));
+    let mut state = pollster::block_on(State::new(&window, world, view, tick_rate));
     let mut last_render_time = std::time::Instant::now();
 
-    state.add_spaceship(
-        1,
-        cgmath::Vector3 {
-            x: 5.0,
-            y: 0.0,
-            z: 0.0,
-        },
-        cgmath::Quaternion::from_axis_angle(cgmath::Vector3::unit_y(), cgmath::Deg(90.0)),
-    );
-
-    state.add_spaceship(
-        2,
-        cgmath::Vector3 {
-            x: -5.0,
-            y: 0.0,
-            z: 0.0,
-        },
-        cgmath::Quaternion::from_axis_angle(cgmath::Vector3::unit_y(), cgmath::Deg(-90.0)),
-    );
-
-    state.add_spaceship(
-        3,
-        cgmath::Vector3 {
-            x: 0.0,
-            y: 5.0,
-            z: 0.0,
-        },
-        cgmath::Quaternion::from_axis_angle(cgmath::Vector3::unit_x(), cgmath::Deg(-90.0)),
-    );
-
-    state.add_spaceship(
-        4,
-        cgmath::Vector3 {
-            x: 0.0,
-            y: -5.0,
-            z: 0.0,
-        },
-        cgmath::Quaternion::from_axis_angle(cgmath::Vector3::unit_x(), cgmath::Deg(90.0)),
-    );
-
-    state.add_spaceship(
-        5,
-        cgmath::Vector3 {
-            x: 0.0,
-            y: 0.0,
-            z: 5.0,
-        },
-        cgmath::Quaternion::new(1.0, 0.0, 0.0, 0.0),
-    );
-
-    state.add_spaceship(
-        6,
-        cgmath::Vector3 {
-            x: 0.0,
-            y: 0.0,
-            z: -5.0,
-        },
-        cgmath::Quaternion::from_axis_angle(cgmath::Vector3::unit_y(), cgmath::Deg(180.0)),
-    );
+    if let Some(replay) = replay {
+        state.start_replay(replay);
+    }
 
     event_loop.run(move |event, _, control_flow| {
         *control_flow = ControlFlow::Poll;
 
         match event {
             Event::DeviceEvent { ref event, .. } => {
+                if let Some((_, recorder)) = &mut recorder {
+                    recorder.record(state.world().tick(), event);
+                }
+
                 state.input(event);
             }
 
@@ -111,6 +237,13 @@ fn main() {
                             virtual_keycode: Some(VirtualKeyCode::Escape),
                             ..
                         } => *control_flow = ControlFlow::Exit,
+                        // a window event rather than device input, so that it isn't
+                        // recorded and replays don't write snapshots of their own
+                        KeyboardInput {
+                            state: ElementState::Released,
+                            virtual_keycode: Some(VirtualKeyCode::F5),
+                            ..
+                        } => save_snapshot(&state),
                         _ => {}
                     },
 
@@ -129,10 +262,10 @@ fn main() {
 
             Event::RedrawRequested(_) => {
                 let now = std::time::Instant::now();
-                let dt = now - last_render_time;
+                let frame_time = now - last_render_time;
 
                 last_render_time = now;
-                state.update(dt);
+                state.update(frame_time);
 
                 match state.render() {
                     Ok(_) => {}
@@ -150,6 +283,20 @@ fn main() {
                 window.request_redraw();
             }
 
+            Event::LoopDestroyed => {
+                if let Some((path, recorder)) = recorder.take() {
+                    let recording = recorder.finish(state.world());
+
+                    match recording.save(&path) {
+                        Ok(_) => println!(
+                            "Recorded {} ticks to {}, state hash {:016x}",
+                            recording.final_tick, path, recording.final_hash
+                        ),
+                        Err(error) => eprintln!("{:#}", error),
+                    }
+                }
+            }
+
             _ => {}
         }
     });
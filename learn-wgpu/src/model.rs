@@ -1,12 +1,20 @@
+use crate::animation::{Clip, Skeleton};
+use crate::geometry::{read_attribute, read_positions, MeshData, MeshFallbacks};
+use crate::gltf_model;
 use crate::texture;
 
 use anyhow::*;
 use rayon::prelude::*;
 use std::ops::Range;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use tobj::LoadOptions;
 use wgpu::util::DeviceExt;
 
+// White, so that the material's base color shows as it is
+pub const FALLBACK_DIFFUSE: [u8; 4] = [255, 255, 255, 255];
+// Straight out of the surface
+pub const FALLBACK_NORMAL: [u8; 4] = [128, 128, 255, 255];
+
 pub trait Vertex {
     fn desc<'a>() -> wgpu::VertexBufferLayout<'a>;
 }
@@ -17,8 +25,8 @@ pub struct ModelVertex {
     position: [f32; 3],
     tex_coords: [f32; 2],
     normal: [f32; 3],
-    tangent: [f32; 3],
-    bitangent: [f32; 3],
+    // The bitangent is cross(normal, tangent) times w
+    tangent: [f32; 4],
 }
 
 impl Vertex for ModelVertex {
@@ -44,22 +52,139 @@ impl Vertex for ModelVertex {
                     shader_location: 2,
                     format: wgpu::VertexFormat::Float32x3,
                 },
-                // Tangent and bitangent
+                // Tangent and bitangent sign
                 wgpu::VertexAttribute {
                     offset: mem::size_of::<[f32; 8]>() as wgpu::BufferAddress,
                     shader_location: 3,
-                    format: wgpu::VertexFormat::Float32x3,
+                    format: wgpu::VertexFormat::Float32x4,
                 },
+            ],
+        }
+    }
+}
+
+// Which joints move a vertex of a skinned mesh, and how much each of them does.
+// Weights that add up to nothing leave the vertex where the mesh has it
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct SkinVertex {
+    pub joints: [u32; 4],
+    pub weights: [f32; 4],
+}
+
+impl Vertex for SkinVertex {
+    fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+        use std::mem;
+
+        // A third vertex buffer, after the model's vertices and the instances
+        wgpu::VertexBufferLayout {
+            array_stride: mem::size_of::<SkinVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::InputStepMode::Vertex,
+            attributes: &[
                 wgpu::VertexAttribute {
-                    offset: mem::size_of::<[f32; 11]>() as wgpu::BufferAddress,
-                    shader_location: 4,
-                    format: wgpu::VertexFormat::Float32x3,
+                    offset: 0,
+                    shader_location: 12,
+                    format: wgpu::VertexFormat::Uint32x4,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[u32; 4]>() as wgpu::BufferAddress,
+                    shader_location: 13,
+                    format: wgpu::VertexFormat::Float32x4,
                 },
             ],
         }
     }
 }
 
+// The shading parameters of a material, as the shader reads them
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct MaterialUniform {
+    // Kd, with the dissolve d as alpha
+    pub base_color: [f32; 4],
+    // Ks
+    pub specular: [f32; 3],
+    // Ns, the specular exponent
+    pub shininess: f32,
+    // Ke
+    pub emissive: [f32; 3],
+    _padding: u32,
+}
+
+impl MaterialUniform {
+    // Anything the MTL leaves out is 0, except that a shininess of 0 would make
+    // the highlight cover the whole surface
+    pub fn from_mtl(mat: &tobj::Material) -> Self {
+        let emissive = mat
+            .unknown_param
+            .get("Ke")
+            .and_then(|value| parse_color(value))
+            .unwrap_or([0.0; 3]);
+
+        MaterialUniform {
+            base_color: [mat.diffuse[0], mat.diffuse[1], mat.diffuse[2], mat.dissolve],
+            specular: mat.specular,
+            shininess: mat.shininess.max(1.0),
+            emissive,
+            _padding: 0,
+        }
+    }
+
+    // Stand-ins for glTF's metallic-roughness factors: metals tint their highlights
+    // with their base color while everything else reflects about 4% of the light,
+    // and rougher surfaces spread their highlights wider
+    pub fn from_pbr(
+        base_color: [f32; 4],
+        metallic: f32,
+        roughness: f32,
+        emissive: [f32; 3],
+    ) -> Self {
+        let reflectance = |channel: f32| 0.04 + (channel - 0.04) * metallic;
+        // a perfectly smooth surface would need an infinitely small highlight
+        let roughness = roughness.max(0.1);
+
+        MaterialUniform {
+            base_color,
+            specular: [
+                reflectance(base_color[0]),
+                reflectance(base_color[1]),
+                reflectance(base_color[2]),
+            ],
+            shininess: (2.0 / roughness.powi(4) - 2.0).max(1.0),
+            emissive,
+            _padding: 0,
+        }
+    }
+}
+
+// For meshes without a material
+impl Default for MaterialUniform {
+    fn default() -> Self {
+        MaterialUniform {
+            base_color: [1.0; 4],
+            specular: [0.0; 3],
+            shininess: 1.0,
+            emissive: [0.0; 3],
+            _padding: 0,
+        }
+    }
+}
+
+// tobj doesn't know about some MTL colors and leaves them as text, like "0.5 0.5 0.5"
+fn parse_color(value: &str) -> Option<[f32; 3]> {
+    let components = value
+        .split_whitespace()
+        .map(|component| component.parse::<f32>().ok())
+        .collect::<Option<Vec<_>>>()?;
+
+    match components[..] {
+        [r, g, b] => Some([r, g, b]),
+        // a single value means the same for every channel
+        [value] => Some([value; 3]),
+        _ => None,
+    }
+}
+
 pub struct Material {
     pub name: String,
     pub diffuse_texture: texture::Texture,
@@ -73,8 +198,14 @@ impl Material {
         name: &str,
         diffuse_texture: texture::Texture,
         normal_texture: texture::Texture,
+        uniform: MaterialUniform,
         layout: &wgpu::BindGroupLayout,
     ) -> Self {
+        let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&format!("{:?} Material Buffer", name)),
+            contents: bytemuck::cast_slice(&[uniform]),
+            usage: wgpu::BufferUsage::UNIFORM,
+        });
         let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
             layout,
             entries: &[
@@ -94,6 +225,10 @@ impl Material {
                     binding: 3,
                     resource: wgpu::BindingResource::Sampler(&normal_texture.sampler),
                 },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: uniform_buffer.as_entire_binding(),
+                },
             ],
             label: Some(name),
         });
@@ -107,25 +242,126 @@ impl Material {
     }
 }
 
+// Appended after the materials a file defines, for any mesh that has no material of
+// its own. Returns its index
+pub fn push_default_material(
+    materials: &mut Vec<Material>,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    layout: &wgpu::BindGroupLayout,
+) -> Result<usize> {
+    materials.push(Material::new(
+        device,
+        "default",
+        texture::Texture::from_color(device, queue, FALLBACK_DIFFUSE, "fallback_texture", false)?,
+        texture::Texture::from_color(device, queue, FALLBACK_NORMAL, "fallback_texture", true)?,
+        MaterialUniform::default(),
+        layout,
+    ));
+
+    Ok(materials.len() - 1)
+}
+
 pub struct Mesh {
     pub name: String,
     pub vertex_buffer: wgpu::Buffer,
     pub index_buffer: wgpu::Buffer,
     pub num_elements: u32,
     pub material: usize,
+    // For the meshes of skinned models
+    pub skin_buffer: Option<wgpu::Buffer>,
+}
+
+impl Mesh {
+    // Fill in whatever vertex data the file left out, and upload it
+    pub fn new(
+        device: &wgpu::Device,
+        name: &str,
+        data: MeshData,
+        material: usize,
+        fallbacks: MeshFallbacks,
+    ) -> Self {
+        let mesh = data.complete(fallbacks);
+        let vertices = (0..mesh.positions.len())
+            .into_par_iter()
+            .map(|i| ModelVertex {
+                position: mesh.positions[i],
+                tex_coords: mesh.tex_coords[i],
+                normal: mesh.normals[i],
+                tangent: mesh.tangents[i],
+            })
+            .collect::<Vec<_>>();
+
+        let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&format!("{:?} Vertex Buffer", name)),
+            contents: bytemuck::cast_slice(&vertices),
+            usage: wgpu::BufferUsage::VERTEX,
+        });
+        let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&format!("{:?} Index Buffer", name)),
+            contents: bytemuck::cast_slice(&mesh.indices),
+            usage: wgpu::BufferUsage::INDEX,
+        });
+        let skin_buffer = mesh.skin.map(|skin| {
+            device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some(&format!("{:?} Skin Buffer", name)),
+                contents: bytemuck::cast_slice(&skin),
+                usage: wgpu::BufferUsage::VERTEX,
+            })
+        });
+
+        Mesh {
+            name: name.to_string(),
+            vertex_buffer,
+            index_buffer,
+            num_elements: mesh.indices.len() as u32,
+            material,
+            skin_buffer,
+        }
+    }
 }
 
 pub struct Model {
     pub meshes: Vec<Mesh>,
     pub materials: Vec<Material>,
+    // Only skinned models have one, and then every mesh has a skin buffer
+    pub skeleton: Option<Skeleton>,
+    pub clips: Vec<Clip>,
 }
 
 impl Model {
+    pub fn clip(&self, name: &str) -> Option<&Clip> {
+        self.clips.iter().find(|clip| clip.name == name)
+    }
+
+    // OBJ, or glTF for .gltf and .glb files
     pub fn load<P: AsRef<Path>>(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
         layout: &wgpu::BindGroupLayout,
         path: P,
+        fallbacks: MeshFallbacks,
+    ) -> Result<Self> {
+        let extension = path
+            .as_ref()
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_ascii_lowercase);
+
+        match extension.as_deref() {
+            Some("gltf") | Some("glb") => {
+                gltf_model::load(device, queue, layout, path.as_ref(), fallbacks)
+            }
+            _ => Self::load_obj(device, queue, layout, path, fallbacks),
+        }
+    }
+
+    fn load_obj<P: AsRef<Path>>(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+        path: P,
+        fallbacks: MeshFallbacks,
     ) -> Result<Self> {
         let (obj_models, obj_materials) = tobj::load_obj(
             path.as_ref(),
@@ -136,19 +372,41 @@ impl Model {
             },
         )?;
 
-        let obj_materials = obj_materials?;
+        // A missing .mtl file only costs the model its materials
+        let obj_materials = obj_materials.unwrap_or_else(|error| {
+            log::warn!(
+                "Could not load the materials of {}: {}",
+                path.as_ref().display(),
+                error
+            );
+            Vec::new()
+        });
         // We're assuming that the texture files are stored with the obj file
         let containing_folder = path.as_ref().parent().context("Directory has no parent")?;
-        let materials = obj_materials
+        let mut materials = obj_materials
             .par_iter()
             .map(|mat| {
                 let mut textures = [
-                    (containing_folder.join(&mat.diffuse_texture), false),
-                    (containing_folder.join(&mat.normal_texture), true),
+                    (&mat.diffuse_texture, FALLBACK_DIFFUSE, false),
+                    (&mat.normal_texture, FALLBACK_NORMAL, true),
                 ]
                 .par_iter()
-                .map(|(texture_path, is_normal_map)| {
-                    texture::Texture::load(device, queue, texture_path, *is_normal_map)
+                .map(|(file_name, fallback_color, is_normal_map)| {
+                    match texture_path(containing_folder, file_name) {
+                        Some(texture_path) => {
+                            texture::Texture::load(device, queue, &texture_path, *is_normal_map)
+                                .with_context(|| {
+                                    format!("Could not load texture {}", texture_path.display())
+                                })
+                        }
+                        None => texture::Texture::from_color(
+                            device,
+                            queue,
+                            *fallback_color,
+                            "fallback_texture",
+                            *is_normal_map,
+                        ),
+                    }
                 })
                 .collect::<Result<Vec<_>>>()?;
 
@@ -160,111 +418,67 @@ impl Model {
                     &mat.name,
                     diffuse_texture,
                     normal_texture,
+                    MaterialUniform::from_mtl(mat),
                     layout,
                 ))
             })
             .collect::<Result<Vec<Material>>>()?;
 
+        let default_material = push_default_material(&mut materials, device, queue, layout)?;
+
+        let file_path = path.as_ref();
         let meshes = obj_models
             .par_iter()
             .map(|model| {
-                let mut vertices = (0..model.mesh.positions.len() / 3)
-                    .into_par_iter()
-                    .map(|i| {
-                        ModelVertex {
-                            position: [
-                                model.mesh.positions[i * 3],
-                                model.mesh.positions[i * 3 + 1],
-                                model.mesh.positions[i * 3 + 2],
-                            ]
-                            .into(),
-                            tex_coords: [
-                                model.mesh.texcoords[i * 2],
-                                model.mesh.texcoords[i * 2 + 1],
-                            ]
-                            .into(),
-                            normal: [
-                                model.mesh.normals[i * 3],
-                                model.mesh.normals[i * 3 + 1],
-                                model.mesh.normals[i * 3 + 2],
-                            ]
-                            .into(),
-                            // We'll calculate these later
-                            tangent: [0.0; 3].into(),
-                            bitangent: [0.0; 3].into(),
-                        }
-                    })
-                    .collect::<Vec<_>>();
-
-                let indices = &model.mesh.indices;
-
-                // Calculate tangents and bitangets. We're going to
-                // use the triangles, so we need to loop through the
-                // indices in chunks of 3
-                for chunk in indices.chunks(3) {
-                    let v0 = vertices[chunk[0] as usize];
-                    let v1 = vertices[chunk[1] as usize];
-                    let v2 = vertices[chunk[2] as usize];
-
-                    let pos0: cgmath::Vector3<_> = v0.position.into();
-                    let pos1: cgmath::Vector3<_> = v1.position.into();
-                    let pos2: cgmath::Vector3<_> = v2.position.into();
-
-                    let uv0: cgmath::Vector2<_> = v0.tex_coords.into();
-                    let uv1: cgmath::Vector2<_> = v1.tex_coords.into();
-                    let uv2: cgmath::Vector2<_> = v2.tex_coords.into();
-
-                    // Calculate the edges of the triangle
-                    let delta_pos1 = pos1 - pos0;
-                    let delta_pos2 = pos2 - pos0;
-
-                    // This will give us a direction to calculate the
-                    // tangent and bitangent
-                    let delta_uv1 = uv1 - uv0;
-                    let delta_uv2 = uv2 - uv0;
-
-                    // Solving the following system of equations will
-                    // give us the tangent and bitangent.
-                    //     delta_pos1 = delta_uv1.x * T + delta_u.y * B
-                    //     delta_pos2 = delta_uv2.x * T + delta_uv2.y * B
-                    // Luckily, the place I found this equation provided
-                    // the solution!
-                    let r = 1.0 / (delta_uv1.x * delta_uv2.y - delta_uv1.y * delta_uv2.x);
-                    let tangent = (delta_pos1 * delta_uv2.y - delta_pos2 * delta_uv1.y) * r;
-                    let bitangent = (delta_pos2 * delta_uv1.x - delta_pos1 * delta_uv2.x) * r;
-
-                    // We'll use the same tangent/bitangent for each vertex in the triangle
-                    vertices[chunk[0] as usize].tangent = tangent.into();
-                    vertices[chunk[1] as usize].tangent = tangent.into();
-                    vertices[chunk[2] as usize].tangent = tangent.into();
-
-                    vertices[chunk[0] as usize].bitangent = bitangent.into();
-                    vertices[chunk[1] as usize].bitangent = bitangent.into();
-                    vertices[chunk[2] as usize].bitangent = bitangent.into();
-                }
-
-                let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                    label: Some(&format!("{:?} Vertex Buffer", model.name)),
-                    contents: bytemuck::cast_slice(&vertices),
-                    usage: wgpu::BufferUsage::VERTEX,
-                });
-                let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                    label: Some(&format!("{:?} Index Buffer", model.name)),
-                    contents: bytemuck::cast_slice(&model.mesh.indices),
-                    usage: wgpu::BufferUsage::INDEX,
-                });
-
-                Ok(Mesh {
-                    name: model.name.clone(),
-                    vertex_buffer,
-                    index_buffer,
-                    num_elements: model.mesh.indices.len() as u32,
-                    material: model.mesh.material_id.unwrap_or(0),
-                })
+                let mesh = &model.mesh;
+                let malformed =
+                    || format!("Malformed mesh {:?} in {}", model.name, file_path.display());
+                let positions =
+                    read_positions(&mesh.positions, &mesh.indices).with_context(malformed)?;
+                let tex_coords =
+                    read_attribute::<2>(&mesh.texcoords, positions.len(), "texture coordinate")
+                        .with_context(malformed)?;
+                let normals = read_attribute::<3>(&mesh.normals, positions.len(), "normal")
+                    .with_context(malformed)?;
+                let data = MeshData {
+                    positions,
+                    tex_coords,
+                    normals,
+                    indices: mesh.indices.clone(),
+                    skin: None,
+                };
+                let material = mesh
+                    .material_id
+                    .filter(|id| *id < default_material)
+                    .unwrap_or(default_material);
+
+                Ok(Mesh::new(device, &model.name, data, material, fallbacks))
             })
             .collect::<Result<Vec<_>>>()?;
 
-        Ok(Self { meshes, materials })
+        Ok(Self {
+            meshes,
+            materials,
+            skeleton: None,
+            clips: Vec::new(),
+        })
+    }
+}
+
+// Where a material's texture is, or None to use a fallback. A texture that
+// isn't there is worth a warning, since the MTL file does ask for it
+fn texture_path(containing_folder: &Path, file_name: &str) -> Option<PathBuf> {
+    if file_name.is_empty() {
+        return None;
+    }
+
+    let path = containing_folder.join(file_name);
+
+    if path.is_file() {
+        Some(path)
+    } else {
+        log::warn!("Texture {} is missing, using a fallback", path.display());
+        None
     }
 }
 
@@ -326,6 +540,11 @@ where
         light: &'b wgpu::BindGroup,
     ) {
         self.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+
+        if let Some(skin_buffer) = &mesh.skin_buffer {
+            self.set_vertex_buffer(2, skin_buffer.slice(..));
+        }
+
         self.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
         self.set_bind_group(0, &material.bind_group, &[]);
         self.set_bind_group(1, &uniforms, &[]);
@@ -437,3 +656,132 @@ where
         }
     }
 }
+
+pub trait DrawShadow<'a, 'b>
+where
+    'b: 'a,
+{
+    fn draw_shadow_mesh_instanced(
+        &mut self,
+        mesh: &'b Mesh,
+        instances: Range<u32>,
+        shadow_pass: &'b wgpu::BindGroup,
+    );
+
+    fn draw_shadow_model_instanced(
+        &mut self,
+        model: &'b Model,
+        instances: Range<u32>,
+        shadow_pass: &'b wgpu::BindGroup,
+    );
+}
+
+impl<'a, 'b> DrawShadow<'a, 'b> for wgpu::RenderPass<'a>
+where
+    'b: 'a,
+{
+    fn draw_shadow_mesh_instanced(
+        &mut self,
+        mesh: &'b Mesh,
+        instances: Range<u32>,
+        shadow_pass: &'b wgpu::BindGroup,
+    ) {
+        self.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+
+        if let Some(skin_buffer) = &mesh.skin_buffer {
+            self.set_vertex_buffer(2, skin_buffer.slice(..));
+        }
+
+        self.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+        self.set_bind_group(0, shadow_pass, &[]);
+        self.draw_indexed(0..mesh.num_elements, 0, instances);
+    }
+
+    fn draw_shadow_model_instanced(
+        &mut self,
+        model: &'b Model,
+        instances: Range<u32>,
+        shadow_pass: &'b wgpu::BindGroup,
+    ) {
+        for mesh in &model.meshes {
+            self.draw_shadow_mesh_instanced(mesh, instances.clone(), shadow_pass);
+        }
+    }
+}
+
+//
+// Tests
+//
+
+#[cfg(test)]
+mod tests {
+    use crate::model::{texture_path, MaterialUniform};
+    use crate::scene::res_dir;
+
+    #[test]
+    fn test_material_from_mtl() {
+        let mat = tobj::Material {
+            diffuse: [1.0, 0.5, 0.25],
+            specular: [0.5; 3],
+            shininess: 324.0,
+            unknown_param: vec![("Ke".to_string(), "0.1 0.2 0.3".to_string())]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        };
+
+        let uniform = MaterialUniform::from_mtl(&mat);
+
+        assert_eq!(uniform.base_color, [1.0, 0.5, 0.25, 1.0]);
+        assert_eq!(uniform.specular, [0.5; 3]);
+        assert_eq!(uniform.shininess, 324.0);
+        assert_eq!(uniform.emissive, [0.1, 0.2, 0.3]);
+
+        // Like the spaceship's materials, which only have a Kd
+        let mat = tobj::Material {
+            diffuse: [0.27, 0.29, 0.34],
+            unknown_param: vec![("Ke".to_string(), "bright".to_string())]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        };
+
+        let uniform = MaterialUniform::from_mtl(&mat);
+
+        assert_eq!(uniform.specular, [0.0; 3]);
+        assert_eq!(uniform.shininess, 1.0);
+        assert_eq!(uniform.emissive, [0.0; 3]);
+    }
+
+    #[test]
+    fn test_material_from_pbr() {
+        let metal = MaterialUniform::from_pbr([1.0, 0.5, 0.0, 1.0], 1.0, 0.5, [0.0; 3]);
+
+        assert_eq!(metal.specular, [1.0, 0.5, 0.0]);
+        assert_eq!(metal.shininess, 30.0);
+
+        let plastic = MaterialUniform::from_pbr([1.0, 0.5, 0.0, 0.5], 0.0, 1.0, [0.1; 3]);
+
+        assert_eq!(plastic.base_color, [1.0, 0.5, 0.0, 0.5]);
+        assert_eq!(plastic.specular, [0.04; 3]);
+        assert_eq!(plastic.shininess, 1.0);
+        assert_eq!(plastic.emissive, [0.1; 3]);
+
+        // A mirror finish still has a highlight to draw
+        assert!(MaterialUniform::from_pbr([1.0; 4], 0.0, 0.0, [0.0; 3])
+            .shininess
+            .is_finite());
+    }
+
+    #[test]
+    fn test_texture_path() {
+        let res_dir = res_dir();
+
+        assert_eq!(
+            texture_path(&res_dir, "cube-normal.png"),
+            Some(res_dir.join("cube-normal.png"))
+        );
+        assert_eq!(texture_path(&res_dir, ""), None);
+        assert_eq!(texture_path(&res_dir, "missing-normal.png"), None);
+    }
+}
@@ -0,0 +1,297 @@
+use cgmath::{InnerSpace, Vector3, Zero};
+use serde::{Deserialize, Serialize};
+
+// Static scene geometry that entities steer around
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum Obstacle {
+    Sphere {
+        center: Vector3<f32>,
+        radius: f32,
+    },
+    Aabb {
+        min: Vector3<f32>,
+        max: Vector3<f32>,
+    },
+    // Every point p for which normal.dot(p) == distance. The normal points to the free side
+    Plane {
+        normal: Vector3<f32>,
+        distance: f32,
+    },
+}
+
+// Six planes facing inwards, keeping everything within a cube around the origin
+pub fn arena_walls(half_size: f32) -> Vec<Obstacle> {
+    [
+        Vector3::unit_x(),
+        -Vector3::unit_x(),
+        Vector3::unit_y(),
+        -Vector3::unit_y(),
+        Vector3::unit_z(),
+        -Vector3::unit_z(),
+    ]
+    .iter()
+    .map(|normal| Obstacle::Plane {
+        normal: *normal,
+        distance: -half_size,
+    })
+    .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Collision {
+    pub position: Vector3<f32>,
+    pub normal: Vector3<f32>,
+    pub distance: f32,
+}
+
+impl Obstacle {
+    // The first hit along a normalized direction. A ray starting inside the obstacle hits it immediately
+    pub fn ray_cast(
+        &self,
+        origin: Vector3<f32>,
+        direction: Vector3<f32>,
+        max_distance: f32,
+    ) -> Option<Collision> {
+        let hit = match *self {
+            Obstacle::Sphere { center, radius } => {
+                ray_cast_sphere(origin, direction, center, radius)
+            }
+            Obstacle::Aabb { min, max } => ray_cast_aabb(origin, direction, min, max),
+            Obstacle::Plane { normal, distance } => {
+                ray_cast_plane(origin, direction, normal, distance)
+            }
+        };
+
+        hit.filter(|collision| collision.distance <= max_distance)
+    }
+}
+
+fn ray_cast_sphere(
+    origin: Vector3<f32>,
+    direction: Vector3<f32>,
+    center: Vector3<f32>,
+    radius: f32,
+) -> Option<Collision> {
+    let to_origin = origin - center;
+
+    if to_origin.magnitude() < radius {
+        return Some(Collision {
+            position: origin,
+            normal: normal_or_up(to_origin),
+            distance: 0.0,
+        });
+    }
+
+    let b = to_origin.dot(direction);
+    let c = to_origin.magnitude2() - radius * radius;
+    let discriminant = b * b - c;
+
+    if discriminant < 0.0 {
+        return None;
+    }
+
+    let t = -b - discriminant.sqrt();
+
+    if t < 0.0 {
+        return None;
+    }
+
+    let position = origin + direction * t;
+
+    Some(Collision {
+        position,
+        normal: (position - center).normalize(),
+        distance: t,
+    })
+}
+
+fn ray_cast_aabb(
+    origin: Vector3<f32>,
+    direction: Vector3<f32>,
+    min: Vector3<f32>,
+    max: Vector3<f32>,
+) -> Option<Collision> {
+    let mut t_enter = f32::NEG_INFINITY;
+    let mut t_exit = f32::INFINITY;
+    let mut enter_normal = Vector3::zero();
+
+    // Slab test, one axis at a time
+    for axis in 0..3 {
+        let mut axis_normal = Vector3::zero();
+
+        if direction[axis].abs() < f32::EPSILON {
+            if origin[axis] < min[axis] || origin[axis] > max[axis] {
+                return None;
+            }
+
+            continue;
+        }
+
+        let t_min = (min[axis] - origin[axis]) / direction[axis];
+        let t_max = (max[axis] - origin[axis]) / direction[axis];
+        let (t_near, t_far, near_sign) = if t_min < t_max {
+            (t_min, t_max, -1.0)
+        } else {
+            (t_max, t_min, 1.0)
+        };
+
+        if t_near > t_enter {
+            t_enter = t_near;
+            axis_normal[axis] = near_sign;
+            enter_normal = axis_normal;
+        }
+
+        t_exit = t_exit.min(t_far);
+    }
+
+    if t_enter > t_exit || t_exit < 0.0 {
+        return None;
+    }
+
+    if t_enter < 0.0 {
+        // Inside the box, push out through the closest face
+        return Some(Collision {
+            position: origin,
+            normal: closest_aabb_face_normal(origin, min, max),
+            distance: 0.0,
+        });
+    }
+
+    Some(Collision {
+        position: origin + direction * t_enter,
+        normal: enter_normal,
+        distance: t_enter,
+    })
+}
+
+fn ray_cast_plane(
+    origin: Vector3<f32>,
+    direction: Vector3<f32>,
+    normal: Vector3<f32>,
+    distance: f32,
+) -> Option<Collision> {
+    let height = normal.dot(origin) - distance;
+
+    if height < 0.0 {
+        return Some(Collision {
+            position: origin,
+            normal,
+            distance: 0.0,
+        });
+    }
+
+    let approach = normal.dot(direction);
+
+    // Parallel to or moving away from the plane
+    if approach >= 0.0 {
+        return None;
+    }
+
+    let t = -height / approach;
+
+    Some(Collision {
+        position: origin + direction * t,
+        normal,
+        distance: t,
+    })
+}
+
+fn closest_aabb_face_normal(
+    point: Vector3<f32>,
+    min: Vector3<f32>,
+    max: Vector3<f32>,
+) -> Vector3<f32> {
+    let mut closest_distance = f32::INFINITY;
+    let mut normal = Vector3::unit_y();
+
+    for axis in 0..3 {
+        for (face_distance, sign) in [
+            (point[axis] - min[axis], -1.0),
+            (max[axis] - point[axis], 1.0),
+        ] {
+            if face_distance < closest_distance {
+                closest_distance = face_distance;
+                normal = Vector3::zero();
+                normal[axis] = sign;
+            }
+        }
+    }
+
+    normal
+}
+
+fn normal_or_up(vector: Vector3<f32>) -> Vector3<f32> {
+    if vector.magnitude() > 0.0 {
+        vector.normalize()
+    } else {
+        Vector3::unit_y()
+    }
+}
+
+//
+// Tests
+//
+
+#[cfg(test)]
+mod tests {
+    use crate::obstacle::Obstacle;
+    use cgmath::Vector3;
+
+    #[test]
+    fn test_ray_cast_sphere() {
+        let sphere = Obstacle::Sphere {
+            center: Vector3::new(0.0, 0.0, 5.0),
+            radius: 1.0,
+        };
+        let origin = Vector3::new(0.0, 0.0, 0.0);
+
+        let hit = sphere.ray_cast(origin, Vector3::unit_z(), 10.0).unwrap();
+        assert_eq!(hit.position, Vector3::new(0.0, 0.0, 4.0));
+        assert_eq!(hit.normal, Vector3::new(0.0, 0.0, -1.0));
+
+        assert!(sphere.ray_cast(origin, Vector3::unit_z(), 3.0).is_none());
+        assert!(sphere.ray_cast(origin, Vector3::unit_x(), 10.0).is_none());
+    }
+
+    #[test]
+    fn test_ray_cast_aabb() {
+        let aabb = Obstacle::Aabb {
+            min: Vector3::new(2.0, -1.0, -1.0),
+            max: Vector3::new(4.0, 1.0, 1.0),
+        };
+
+        let hit = aabb
+            .ray_cast(Vector3::new(0.0, 0.0, 0.0), Vector3::unit_x(), 10.0)
+            .unwrap();
+        assert_eq!(hit.position, Vector3::new(2.0, 0.0, 0.0));
+        assert_eq!(hit.normal, Vector3::new(-1.0, 0.0, 0.0));
+
+        let inside = aabb
+            .ray_cast(Vector3::new(3.0, 0.9, 0.0), Vector3::unit_x(), 10.0)
+            .unwrap();
+        assert_eq!(inside.distance, 0.0);
+        assert_eq!(inside.normal, Vector3::new(0.0, 1.0, 0.0));
+
+        assert!(aabb
+            .ray_cast(Vector3::new(0.0, 2.0, 0.0), Vector3::unit_x(), 10.0)
+            .is_none());
+    }
+
+    #[test]
+    fn test_ray_cast_plane() {
+        let floor = Obstacle::Plane {
+            normal: Vector3::unit_y(),
+            distance: -2.0,
+        };
+
+        let hit = floor
+            .ray_cast(Vector3::new(1.0, 0.0, 0.0), -Vector3::unit_y(), 5.0)
+            .unwrap();
+        assert_eq!(hit.position, Vector3::new(1.0, -2.0, 0.0));
+        assert_eq!(hit.distance, 2.0);
+
+        assert!(floor
+            .ray_cast(Vector3::new(1.0, 0.0, 0.0), Vector3::unit_y(), 5.0)
+            .is_none());
+    }
+}
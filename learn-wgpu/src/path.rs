@@ -0,0 +1,254 @@
+use cgmath::{InnerSpace, Vector3};
+use serde::{Deserialize, Serialize};
+
+// How many straight segments approximate one span of a spline
+const SAMPLES_PER_SPAN: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum PathKind {
+    Polyline,
+    CatmullRom,
+}
+
+// A route through control points. Parameters along the path are distances from its start
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "PathDescription", into = "PathDescription")]
+pub struct Path {
+    kind: PathKind,
+    control_points: Vec<Vector3<f32>>,
+    looped: bool,
+    // The path flattened into short segments, and the distance along the path to each sample
+    samples: Vec<Vector3<f32>>,
+    distances: Vec<f32>,
+}
+
+impl Path {
+    pub fn new(kind: PathKind, control_points: Vec<Vector3<f32>>, looped: bool) -> Self {
+        let samples = match kind {
+            PathKind::Polyline => {
+                let mut samples = control_points.clone();
+
+                if looped && !control_points.is_empty() {
+                    samples.push(control_points[0]);
+                }
+
+                samples
+            }
+            PathKind::CatmullRom => sample_catmull_rom(&control_points, looped),
+        };
+
+        let mut distances = Vec::with_capacity(samples.len());
+        let mut total_distance = 0.0;
+
+        for (index, sample) in samples.iter().enumerate() {
+            if index > 0 {
+                total_distance += (sample - samples[index - 1]).magnitude();
+            }
+
+            distances.push(total_distance);
+        }
+
+        Path {
+            kind,
+            control_points,
+            looped,
+            samples,
+            distances,
+        }
+    }
+
+    pub fn length(&self) -> f32 {
+        self.distances.last().copied().unwrap_or(0.0)
+    }
+
+    // Looped paths wrap the parameter around, open paths clamp it to their ends
+    pub fn position_at(&self, param: f32) -> Vector3<f32> {
+        if self.samples.len() < 2 {
+            return self
+                .samples
+                .first()
+                .copied()
+                .unwrap_or_else(cgmath::Zero::zero);
+        }
+
+        let param = self.normalize_param(param);
+        let segment = match self.distances.iter().position(|distance| *distance > param) {
+            Some(index) => index - 1,
+            None => self.samples.len() - 2,
+        };
+        let segment_length = self.distances[segment + 1] - self.distances[segment];
+        let t = if segment_length > 0.0 {
+            ((param - self.distances[segment]) / segment_length).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+
+        self.samples[segment] + (self.samples[segment + 1] - self.samples[segment]) * t
+    }
+
+    // The parameter of the point on the path closest to the position
+    pub fn closest_param(&self, position: Vector3<f32>) -> f32 {
+        let mut closest_distance = f32::INFINITY;
+        let mut closest_param = 0.0;
+
+        for segment in 0..self.samples.len().saturating_sub(1) {
+            let start = self.samples[segment];
+            let end = self.samples[segment + 1];
+            let segment_vector = end - start;
+            let segment_length = segment_vector.magnitude();
+
+            let t = if segment_length > 0.0 {
+                ((position - start).dot(segment_vector) / (segment_length * segment_length))
+                    .clamp(0.0, 1.0)
+            } else {
+                0.0
+            };
+
+            let distance = (start + segment_vector * t - position).magnitude();
+
+            if distance < closest_distance {
+                closest_distance = distance;
+                closest_param = self.distances[segment] + segment_length * t;
+            }
+        }
+
+        closest_param
+    }
+
+    pub fn is_past_end(&self, param: f32) -> bool {
+        !self.looped && param >= self.length()
+    }
+
+    fn normalize_param(&self, param: f32) -> f32 {
+        let length = self.length();
+
+        if self.looped && length > 0.0 {
+            param.rem_euclid(length)
+        } else {
+            param.clamp(0.0, length)
+        }
+    }
+}
+
+// Only the control points are stored, the samples are rebuilt when loading
+#[derive(Serialize, Deserialize)]
+struct PathDescription {
+    kind: PathKind,
+    control_points: Vec<Vector3<f32>>,
+    looped: bool,
+}
+
+impl From<PathDescription> for Path {
+    fn from(description: PathDescription) -> Self {
+        Path::new(
+            description.kind,
+            description.control_points,
+            description.looped,
+        )
+    }
+}
+
+impl From<Path> for PathDescription {
+    fn from(path: Path) -> Self {
+        PathDescription {
+            kind: path.kind,
+            control_points: path.control_points,
+            looped: path.looped,
+        }
+    }
+}
+
+fn sample_catmull_rom(control_points: &[Vector3<f32>], looped: bool) -> Vec<Vector3<f32>> {
+    let count = control_points.len();
+
+    if count < 2 {
+        return control_points.to_vec();
+    }
+
+    // Open splines repeat their end points so that the curve passes through them
+    let point = |index: isize| -> Vector3<f32> {
+        if looped {
+            control_points[index.rem_euclid(count as isize) as usize]
+        } else {
+            control_points[index.clamp(0, count as isize - 1) as usize]
+        }
+    };
+    let span_count = if looped { count } else { count - 1 };
+    let mut samples = Vec::with_capacity(span_count * SAMPLES_PER_SPAN + 1);
+
+    for span in 0..span_count as isize {
+        let (p0, p1, p2, p3) = (
+            point(span - 1),
+            point(span),
+            point(span + 1),
+            point(span + 2),
+        );
+
+        for sample in 0..SAMPLES_PER_SPAN {
+            let t = sample as f32 / SAMPLES_PER_SPAN as f32;
+            let t2 = t * t;
+            let t3 = t2 * t;
+
+            samples.push(
+                (p1 * 2.0
+                    + (p2 - p0) * t
+                    + (p0 * 2.0 - p1 * 5.0 + p2 * 4.0 - p3) * t2
+                    + (p1 * 3.0 - p0 - p2 * 3.0 + p3) * t3)
+                    * 0.5,
+            );
+        }
+    }
+
+    samples.push(point(span_count as isize));
+    samples
+}
+
+//
+// Tests
+//
+
+#[cfg(test)]
+mod tests {
+    use crate::path::{Path, PathKind};
+    use cgmath::{InnerSpace, Vector3};
+
+    #[test]
+    fn test_polyline_params() {
+        let path = Path::new(
+            PathKind::Polyline,
+            vec![
+                Vector3::new(0.0, 0.0, 0.0),
+                Vector3::new(2.0, 0.0, 0.0),
+                Vector3::new(2.0, 2.0, 0.0),
+            ],
+            false,
+        );
+
+        assert_eq!(path.length(), 4.0);
+        assert_eq!(path.position_at(3.0), Vector3::new(2.0, 1.0, 0.0));
+        assert_eq!(path.position_at(10.0), Vector3::new(2.0, 2.0, 0.0));
+        assert_eq!(path.closest_param(Vector3::new(1.0, -1.0, 0.0)), 1.0);
+        assert_eq!(path.closest_param(Vector3::new(3.0, 1.5, 0.0)), 3.5);
+    }
+
+    #[test]
+    fn test_catmull_rom_passes_through_control_points() {
+        let control_points = vec![
+            Vector3::new(5.0, 0.0, 0.0),
+            Vector3::new(0.0, 5.0, 0.0),
+            Vector3::new(-5.0, 0.0, 0.0),
+            Vector3::new(0.0, -5.0, 0.0),
+        ];
+        let path = Path::new(PathKind::CatmullRom, control_points.clone(), true);
+
+        for control_point in control_points {
+            let param = path.closest_param(control_point);
+
+            assert!((path.position_at(param) - control_point).magnitude() < 0.001);
+        }
+
+        // A looped path wraps around
+        let start = path.position_at(0.0);
+        assert!((path.position_at(path.length()) - start).magnitude() < 0.001);
+    }
+}
@@ -0,0 +1,232 @@
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::path::Path;
+use winit::event::{DeviceEvent, ElementState, KeyboardInput, MouseScrollDelta};
+
+use crate::scene::SceneView;
+use crate::timestep::FixedTimestep;
+use crate::world::World;
+
+// The device events that State::input reacts to, in a form that can be written to a file
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum RecordedInput {
+    Key(KeyboardInput),
+    MouseWheel { delta: MouseScrollDelta },
+    Button { button: u32, state: ElementState },
+    MouseMotion { delta: (f64, f64) },
+}
+
+impl RecordedInput {
+    pub fn from_device_event(event: &DeviceEvent) -> Option<Self> {
+        match event {
+            DeviceEvent::Key(input) => Some(RecordedInput::Key(*input)),
+            DeviceEvent::MouseWheel { delta } => Some(RecordedInput::MouseWheel { delta: *delta }),
+            DeviceEvent::Button { button, state } => Some(RecordedInput::Button {
+                button: *button,
+                state: *state,
+            }),
+            DeviceEvent::MouseMotion { delta } => {
+                Some(RecordedInput::MouseMotion { delta: *delta })
+            }
+            _ => None,
+        }
+    }
+
+    pub fn to_device_event(self) -> DeviceEvent {
+        match self {
+            RecordedInput::Key(input) => DeviceEvent::Key(input),
+            RecordedInput::MouseWheel { delta } => DeviceEvent::MouseWheel { delta },
+            RecordedInput::Button { button, state } => DeviceEvent::Button { button, state },
+            RecordedInput::MouseMotion { delta } => DeviceEvent::MouseMotion { delta },
+        }
+    }
+}
+
+// An input and the world tick it arrived on, i.e. before that tick was simulated
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct RecordedEvent {
+    pub tick: u64,
+    pub input: RecordedInput,
+}
+
+// Everything needed to run a session again and check that it ended up the same
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Recording {
+    // None when the session resumed a snapshot rather than building a scene
+    pub seed: Option<u64>,
+    pub tick_rate: u32,
+    pub initial_world: World,
+    pub view: SceneView,
+    pub events: Vec<RecordedEvent>,
+    pub final_tick: u64,
+    pub final_hash: u64,
+}
+
+impl Recording {
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("Could not open recording {}", path.display()))?;
+
+        serde_json::from_reader(std::io::BufReader::new(file))
+            .with_context(|| format!("Could not read recording {}", path.display()))
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        let file = std::fs::File::create(path)
+            .with_context(|| format!("Could not create recording {}", path.display()))?;
+
+        serde_json::to_writer(std::io::BufWriter::new(file), self)
+            .with_context(|| format!("Could not write recording {}", path.display()))
+    }
+
+    // Simulate the recording up to its final tick without a window. Input only
+    // moves the camera and pauses the simulation, neither of which changes the
+    // world, so the events don't need to be fed in here
+    pub fn replay_headless(&self) -> World {
+        let dt = FixedTimestep::new(self.tick_rate).tick();
+        let mut world = self.initial_world.clone();
+
+        while world.tick() < self.final_tick {
+            world.step(dt);
+        }
+
+        world
+    }
+
+    pub fn verify(&self, world: &World) -> bool {
+        world.tick() == self.final_tick && world.state_hash() == self.final_hash
+    }
+}
+
+// Captures a live session
+pub struct Recorder {
+    recording: Recording,
+}
+
+impl Recorder {
+    pub fn new(seed: Option<u64>, tick_rate: u32, initial_world: &World, view: SceneView) -> Self {
+        Recorder {
+            recording: Recording {
+                seed,
+                tick_rate,
+                initial_world: initial_world.clone(),
+                view,
+                events: Vec::new(),
+                final_tick: 0,
+                final_hash: 0,
+            },
+        }
+    }
+
+    pub fn record(&mut self, tick: u64, event: &DeviceEvent) {
+        if let Some(input) = RecordedInput::from_device_event(event) {
+            self.recording.events.push(RecordedEvent { tick, input });
+        }
+    }
+
+    pub fn finish(mut self, final_world: &World) -> Recording {
+        self.recording.final_tick = final_world.tick();
+        self.recording.final_hash = final_world.state_hash();
+        self.recording
+    }
+}
+
+// Feeds a recording's events back in on the ticks they were recorded on
+pub struct Replay {
+    events: VecDeque<RecordedEvent>,
+    pub final_tick: u64,
+    pub final_hash: u64,
+}
+
+impl Replay {
+    pub fn new(recording: &Recording) -> Self {
+        Replay {
+            events: recording.events.iter().copied().collect(),
+            final_tick: recording.final_tick,
+            final_hash: recording.final_hash,
+        }
+    }
+
+    // The events due before the given tick is simulated
+    pub fn take_due(&mut self, tick: u64) -> Vec<DeviceEvent> {
+        let mut due = Vec::new();
+
+        while let Some(event) = self.events.front() {
+            if event.tick > tick {
+                break;
+            }
+
+            due.push(event.input.to_device_event());
+            self.events.pop_front();
+        }
+
+        due
+    }
+}
+
+//
+// Tests
+//
+
+#[cfg(test)]
+mod tests {
+    use crate::recording::{Recorder, Recording, Replay};
+    use crate::scene::{CameraDescription, ModelsDescription, SceneView, ShadowsDescription};
+    use crate::world::World;
+    use cgmath::{Quaternion, Vector3};
+    use std::time::Duration;
+    use winit::event::{DeviceEvent, ElementState};
+
+    #[test]
+    fn test_replay_matches_recording() {
+        let mut world = World::new(11);
+
+        for id in 1..=4 {
+            world.add_spaceship(
+                id,
+                Vector3::new(id as f32 * 3.0, 1.0, -2.0),
+                Quaternion::new(1.0, 0.0, 0.0, 0.0),
+            );
+        }
+
+        let view = SceneView {
+            camera: CameraDescription {
+                position: Vector3::new(0.0, 0.0, 10.0),
+                yaw_degrees: -90.0,
+                pitch_degrees: 0.0,
+            },
+            models: ModelsDescription::default(),
+            shadows: ShadowsDescription::default(),
+        };
+        let mut recorder = Recorder::new(Some(11), 50, &world, view);
+        let press = DeviceEvent::Button {
+            button: 1,
+            state: ElementState::Pressed,
+        };
+
+        for tick in 0..120 {
+            if tick % 40 == 0 {
+                recorder.record(world.tick(), &press);
+            }
+
+            world.step(Duration::from_millis(20));
+        }
+
+        // Through a file format round trip, like a real recording
+        let recording = recorder.finish(&world);
+        let json = serde_json::to_string(&recording).unwrap();
+        let loaded: Recording = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(loaded.events.len(), 3);
+        assert!(loaded.verify(&loaded.replay_headless()));
+        assert!(!loaded.verify(&loaded.initial_world));
+
+        let mut replay = Replay::new(&loaded);
+        assert_eq!(replay.take_due(0), vec![press.clone()]);
+        assert!(replay.take_due(39).is_empty());
+        assert_eq!(replay.take_due(100).len(), 2);
+    }
+}
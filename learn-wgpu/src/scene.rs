@@ -0,0 +1,670 @@
+use anyhow::{anyhow, bail, Context};
+use cgmath::{prelude::*, Deg, Quaternion, Vector3};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::{Path as FilePath, PathBuf};
+
+use crate::entity::{AnimationPlayback, Light, LightKind, Spaceship};
+use crate::geometry::MeshFallbacks;
+use crate::obstacle::{self, Obstacle};
+use crate::path::Path;
+use crate::steering::WanderProps;
+use crate::world::{World, MAX_LIGHTS, MAX_SPACESHIPS};
+
+// Larger shadow maps exceed the texture size limit of common GPUs
+const MAX_SHADOW_MAP_SIZE: u32 = 8192;
+
+// Everything a scenario starts from, authored as JSON. See res/scenes/default.json
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Scene {
+    pub camera: CameraDescription,
+    #[serde(default)]
+    pub models: ModelsDescription,
+    #[serde(default)]
+    pub shadows: ShadowsDescription,
+    // Walls keep everything within a cube of this half size around the origin
+    pub arena_half_size: f32,
+    #[serde(default)]
+    pub lights: Vec<LightDescription>,
+    #[serde(default)]
+    pub obstacles: Vec<Obstacle>,
+    #[serde(default)]
+    pub spaceships: Vec<SpaceshipDescription>,
+    #[serde(default)]
+    pub flocks: Vec<FlockDescription>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CameraDescription {
+    pub position: Vector3<f32>,
+    pub yaw_degrees: f32,
+    pub pitch_degrees: f32,
+}
+
+// Model files, relative to the res directory. OBJ, or glTF for .gltf and .glb files
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ModelsDescription {
+    pub spaceship: String,
+    pub light: String,
+    // What to make up for models whose files have no normals or texture coordinates
+    #[serde(default)]
+    pub fallbacks: MeshFallbacks,
+}
+
+impl Default for ModelsDescription {
+    fn default() -> Self {
+        ModelsDescription {
+            spaceship: "spaceship.obj".to_string(),
+            light: "cube.obj".to_string(),
+            fallbacks: MeshFallbacks::default(),
+        }
+    }
+}
+
+// How shadow maps are rendered. The first point or spot light and the first
+// directional light cast shadows
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct ShadowsDescription {
+    // Texels along each side of a shadow map, or of each cube face
+    pub map_size: u32,
+    // Depth biases added while rendering the shadow maps, against shadow acne
+    pub constant_bias: i32,
+    pub slope_scale_bias: f32,
+    // Half the width of the box around the origin that the directional shadow covers
+    pub directional_half_size: f32,
+}
+
+impl Default for ShadowsDescription {
+    fn default() -> Self {
+        ShadowsDescription {
+            map_size: 1024,
+            constant_bias: 2,
+            slope_scale_bias: 2.0,
+            directional_half_size: 26.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LightDescription {
+    // Unused by directional lights
+    #[serde(default = "Vector3::zero")]
+    pub position: Vector3<f32>,
+    pub color: [f32; 3],
+    // A point light when left out
+    #[serde(default)]
+    pub kind: LightKind,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AxisAngle {
+    pub axis: Vector3<f32>,
+    pub degrees: f32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SpaceshipDescription {
+    pub id: u16,
+    pub position: Vector3<f32>,
+    // No rotation when left out
+    pub orientation: Option<AxisAngle>,
+    pub wander: Option<WanderDescription>,
+    pub patrol_path: Option<Path>,
+    #[serde(default = "default_look_where_you_are_going")]
+    pub look_where_you_are_going: bool,
+    // For skinned spaceship models. Drawn in the rest pose when left out
+    pub animation: Option<AnimationDescription>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AnimationDescription {
+    // The name of a clip in the spaceship model
+    pub clip: String,
+    #[serde(default = "default_animation_speed")]
+    pub speed: f32,
+    #[serde(default = "default_animation_looping")]
+    pub looping: bool,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WanderDescription {
+    pub offset: f32,
+    pub radius: f32,
+    pub jitter_rate: f32,
+}
+
+// A group of wandering ships with consecutive ids, scattered randomly within a box
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FlockDescription {
+    pub first_id: u16,
+    pub count: u16,
+    pub min: Vector3<f32>,
+    pub max: Vector3<f32>,
+}
+
+// The parts of a scene that only matter for drawing it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SceneView {
+    pub camera: CameraDescription,
+    pub models: ModelsDescription,
+    #[serde(default)]
+    pub shadows: ShadowsDescription,
+}
+
+fn default_look_where_you_are_going() -> bool {
+    true
+}
+
+fn default_animation_speed() -> f32 {
+    1.0
+}
+
+fn default_animation_looping() -> bool {
+    true
+}
+
+pub fn res_dir() -> PathBuf {
+    FilePath::new(env!("OUT_DIR")).join("res")
+}
+
+pub fn default_scene_path() -> PathBuf {
+    res_dir().join("scenes").join("default.json")
+}
+
+impl Scene {
+    pub fn load(path: impl AsRef<FilePath>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read scene {}", path.display()))?;
+
+        Scene::parse(&contents).with_context(|| format!("Invalid scene {}", path.display()))
+    }
+
+    pub fn parse(contents: &str) -> anyhow::Result<Self> {
+        let scene: Scene = serde_json::from_str(contents)?;
+
+        scene.validate()?;
+        Ok(scene)
+    }
+
+    pub fn view(&self) -> SceneView {
+        SceneView {
+            camera: self.camera,
+            models: self.models.clone(),
+            shadows: self.shadows,
+        }
+    }
+
+    pub fn build_world(&self, seed: u64) -> World {
+        let mut world = World::new(seed);
+
+        world.set_obstacles(
+            obstacle::arena_walls(self.arena_half_size)
+                .into_iter()
+                .chain(self.obstacles.iter().copied())
+                .collect(),
+        );
+
+        if !self.lights.is_empty() {
+            world.set_lights(
+                self.lights
+                    .iter()
+                    .map(|description| {
+                        let mut light = Light::new(description.position, description.color);
+
+                        light.kind = description.kind;
+                        light
+                    })
+                    .collect(),
+            );
+        }
+
+        for description in self.spaceships.iter() {
+            let orientation = description
+                .orientation
+                .map_or(Quaternion::one(), |orientation| {
+                    Quaternion::from_axis_angle(
+                        orientation.axis.normalize(),
+                        Deg(orientation.degrees),
+                    )
+                });
+            let mut spaceship = Spaceship::new(description.id, description.position, orientation);
+
+            if let Some(wander) = description.wander {
+                spaceship.wander =
+                    WanderProps::new(wander.offset, wander.radius, wander.jitter_rate);
+            }
+
+            spaceship.patrol_path = description.patrol_path.clone();
+            spaceship.look_where_you_are_going = description.look_where_you_are_going;
+            spaceship.animation = description.animation.as_ref().map(|animation| {
+                AnimationPlayback::new(&animation.clip, animation.speed, animation.looping)
+            });
+            world.insert_spaceship(spaceship);
+        }
+
+        // A separate stream from the simulation's, so that spawning doesn't shift its random choices
+        let mut rng = ChaCha8Rng::seed_from_u64(seed);
+
+        rng.set_stream(1);
+
+        for flock in self.flocks.iter() {
+            for id in flock.first_id..flock.first_id + flock.count {
+                world.add_spaceship(
+                    id,
+                    Vector3::new(
+                        rng.gen_range(flock.min.x..flock.max.x),
+                        rng.gen_range(flock.min.y..flock.max.y),
+                        rng.gen_range(flock.min.z..flock.max.z),
+                    ),
+                    Quaternion::from_axis_angle(Vector3::unit_y(), Deg(rng.gen_range(0.0..360.0))),
+                );
+            }
+        }
+
+        world
+    }
+
+    // Catch mistakes that would otherwise show up as panics or odd behavior at runtime
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.arena_half_size.is_nan() || self.arena_half_size <= 0.0 {
+            bail!(
+                "arena_half_size must be positive, got {}",
+                self.arena_half_size
+            );
+        }
+
+        validate_view(&self.view())?;
+        validate_lights(self.lights.iter().map(|light| &light.kind))?;
+        validate_obstacles(&self.obstacles)?;
+        validate_spaceship_count(
+            self.spaceships.len()
+                + self
+                    .flocks
+                    .iter()
+                    .map(|flock| flock.count as usize)
+                    .sum::<usize>(),
+        )?;
+
+        let mut ids = HashSet::new();
+
+        for spaceship in self.spaceships.iter() {
+            if !ids.insert(spaceship.id) {
+                bail!("spaceship id {} is used more than once", spaceship.id);
+            }
+
+            if let Some(orientation) = spaceship.orientation {
+                if orientation.axis.magnitude() == 0.0 {
+                    bail!("spaceship {} has a zero rotation axis", spaceship.id);
+                }
+            }
+
+            if let Some(wander) = spaceship.wander {
+                validate_wander_radius(spaceship.id, wander.radius)?;
+            }
+
+            if let Some(patrol_path) = &spaceship.patrol_path {
+                validate_patrol_path(spaceship.id, patrol_path)?;
+            }
+
+            if let Some(animation) = &spaceship.animation {
+                validate_animation(spaceship.id, &animation.clip, animation.speed)?;
+            }
+        }
+
+        for flock in self.flocks.iter() {
+            let last_id = flock.first_id.checked_add(flock.count).ok_or_else(|| {
+                anyhow!(
+                    "the flock starting at id {} runs out of ids",
+                    flock.first_id
+                )
+            })?;
+
+            for id in flock.first_id..last_id {
+                if !ids.insert(id) {
+                    bail!("spaceship id {} is used more than once", id);
+                }
+            }
+
+            let is_empty_box = flock.min.x >= flock.max.x
+                || flock.min.y >= flock.max.y
+                || flock.min.z >= flock.max.z;
+
+            if is_empty_box {
+                bail!(
+                    "the flock starting at id {} needs min to be below max on every axis",
+                    flock.first_id
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// The checks of a scene's view, for snapshots that carry one of their own
+pub fn validate_view(view: &SceneView) -> anyhow::Result<()> {
+    if view.shadows.map_size == 0 || view.shadows.map_size > MAX_SHADOW_MAP_SIZE {
+        bail!(
+            "the shadow map_size must be between 1 and {}, got {}",
+            MAX_SHADOW_MAP_SIZE,
+            view.shadows.map_size
+        );
+    }
+
+    if view.shadows.directional_half_size.is_nan() || view.shadows.directional_half_size <= 0.0 {
+        bail!(
+            "the shadow directional_half_size must be positive, got {}",
+            view.shadows.directional_half_size
+        );
+    }
+
+    for (name, file) in [
+        ("spaceship", &view.models.spaceship),
+        ("light", &view.models.light),
+    ] {
+        if !res_dir().join(file).is_file() {
+            bail!("the {} model {:?} is not in the res directory", name, file);
+        }
+    }
+
+    Ok(())
+}
+
+// The checks of a scene, applied to a world that was saved mid-simulation
+// rather than built from one
+pub fn validate_world(world: &World) -> anyhow::Result<()> {
+    validate_lights(world.lights().iter().map(|light| &light.kind))?;
+    validate_obstacles(world.obstacles())?;
+    validate_spaceship_count(world.spaceships().count())?;
+
+    for spaceship in world.spaceships() {
+        validate_wander_radius(spaceship.id, spaceship.wander.radius)?;
+
+        if let Some(patrol_path) = &spaceship.patrol_path {
+            validate_patrol_path(spaceship.id, patrol_path)?;
+        }
+
+        if let Some(animation) = &spaceship.animation {
+            validate_animation(spaceship.id, &animation.clip, animation.speed)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn validate_lights<'a>(kinds: impl ExactSizeIterator<Item = &'a LightKind>) -> anyhow::Result<()> {
+    if kinds.len() > MAX_LIGHTS {
+        bail!(
+            "at most {} lights are supported, the scene has {}",
+            MAX_LIGHTS,
+            kinds.len()
+        );
+    }
+
+    for (index, kind) in kinds.enumerate() {
+        validate_light_kind(kind).with_context(|| format!("light {}", index))?;
+    }
+
+    Ok(())
+}
+
+fn validate_obstacles(obstacles: &[Obstacle]) -> anyhow::Result<()> {
+    for (index, obstacle) in obstacles.iter().enumerate() {
+        validate_obstacle(obstacle).with_context(|| format!("obstacle {}", index))?;
+    }
+
+    Ok(())
+}
+
+fn validate_spaceship_count(count: usize) -> anyhow::Result<()> {
+    if count > MAX_SPACESHIPS {
+        bail!(
+            "at most {} spaceships are supported, the scene has {}",
+            MAX_SPACESHIPS,
+            count
+        );
+    }
+
+    Ok(())
+}
+
+fn validate_wander_radius(id: u16, radius: f32) -> anyhow::Result<()> {
+    if radius.is_nan() || radius <= 0.0 {
+        bail!("spaceship {} has a wander radius of {}", id, radius);
+    }
+
+    Ok(())
+}
+
+fn validate_patrol_path(id: u16, patrol_path: &Path) -> anyhow::Result<()> {
+    if patrol_path.length() == 0.0 {
+        bail!(
+            "the patrol path of spaceship {} needs at least two distinct control points",
+            id
+        );
+    }
+
+    Ok(())
+}
+
+fn validate_animation(id: u16, clip: &str, speed: f32) -> anyhow::Result<()> {
+    if clip.is_empty() {
+        bail!("the animation of spaceship {} has no clip", id);
+    }
+
+    if !speed.is_finite() {
+        bail!("the animation of spaceship {} has a speed of {}", id, speed);
+    }
+
+    Ok(())
+}
+
+fn validate_light_kind(kind: &LightKind) -> anyhow::Result<()> {
+    let (range, direction, cone) = match *kind {
+        LightKind::Point { range } => (Some(range), None, None),
+        LightKind::Directional { direction } => (None, Some(direction), None),
+        LightKind::Spot {
+            range,
+            direction,
+            inner_cone_degrees,
+            outer_cone_degrees,
+        } => (
+            Some(range),
+            Some(direction),
+            Some((inner_cone_degrees, outer_cone_degrees)),
+        ),
+    };
+
+    if let Some(range) = range {
+        if range.is_nan() || range <= 0.0 {
+            bail!("the range must be positive, got {}", range);
+        }
+    }
+
+    if let Some(direction) = direction {
+        if direction.magnitude() == 0.0 {
+            bail!("the direction must not be zero");
+        }
+    }
+
+    if let Some((inner, outer)) = cone {
+        if !(0.0 <= inner && inner <= outer && outer < 90.0) {
+            bail!(
+                "the cone angles need 0 <= inner <= outer < 90 degrees, got {} and {}",
+                inner,
+                outer
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// Obstacles that aren't solid shapes make the ray casts in avoid_obstacles return NaNs
+fn validate_obstacle(obstacle: &Obstacle) -> anyhow::Result<()> {
+    match *obstacle {
+        Obstacle::Sphere { radius, .. } => {
+            if radius.is_nan() || radius <= 0.0 {
+                bail!("the sphere radius must be positive, got {}", radius);
+            }
+        }
+        Obstacle::Aabb { min, max } => {
+            let is_inverted = !(min.x <= max.x && min.y <= max.y && min.z <= max.z);
+
+            if is_inverted {
+                bail!("the box needs min to be at most max on every axis");
+            }
+        }
+        Obstacle::Plane { normal, distance } => {
+            let length = normal.magnitude();
+
+            if length.is_nan() || length == 0.0 || distance.is_nan() {
+                bail!("the plane needs a non-zero normal and a distance");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+//
+// Tests
+//
+
+#[cfg(test)]
+mod tests {
+    use crate::scene::{default_scene_path, Scene};
+
+    #[test]
+    fn test_default_scene() {
+        let scene = Scene::load(default_scene_path()).unwrap();
+        let world = scene.build_world(1);
+
+        assert_eq!(world.spaceships().count(), 30);
+        assert_eq!(world.lights().len(), 3);
+        assert!(world
+            .spaceships()
+            .filter(|spaceship| spaceship.id <= 6)
+            .all(|spaceship| spaceship.patrol_path.is_some()));
+    }
+
+    #[test]
+    fn test_invalid_scenes() {
+        let error = |json: &str| format!("{:#}", Scene::parse(json).unwrap_err());
+        let camera = r#""camera": {"position": {"x": 0, "y": 0, "z": 0}, "yaw_degrees": 0, "pitch_degrees": 0}"#;
+
+        assert!(error(&format!(
+            r#"{{{}, "arena_half_size": 10, "speed": 1}}"#,
+            camera
+        ))
+        .contains("unknown field `speed`"));
+        assert!(error(&format!(r#"{{{}}}"#, camera)).contains("missing field `arena_half_size`"));
+        assert!(error(&format!(
+            r#"{{{}, "arena_half_size": 10, "spaceships": [
+                {{"id": 1, "position": {{"x": 0, "y": 0, "z": 0}}}},
+                {{"id": 1, "position": {{"x": 1, "y": 0, "z": 0}}}}
+            ]}}"#,
+            camera
+        ))
+        .contains("spaceship id 1 is used more than once"));
+        assert!(error(&format!(
+            r#"{{{}, "arena_half_size": 10, "models": {{"spaceship": "missing.obj", "light": "cube.obj"}}}}"#,
+            camera
+        ))
+        .contains("\"missing.obj\" is not in the res directory"));
+        assert!(error(&format!(
+            r#"{{{}, "arena_half_size": 10, "lights": [
+                {{"color": [1, 1, 1], "kind": {{"type": "spot", "range": 5, "direction": {{"x": 0, "y": -1, "z": 0}}, "inner_cone_degrees": 30, "outer_cone_degrees": 20}}}}
+            ]}}"#,
+            camera
+        ))
+        .contains("light 0: the cone angles need 0 <= inner <= outer < 90 degrees"));
+        assert!(error(&format!(
+            r#"{{{}, "arena_half_size": 10, "spaceships": [
+                {{"id": 1, "position": {{"x": 0, "y": 0, "z": 0}}, "animation": {{"clip": ""}}}}
+            ]}}"#,
+            camera
+        ))
+        .contains("the animation of spaceship 1 has no clip"));
+
+        for (obstacle, message) in [
+            (
+                r#"{"Sphere": {"center": {"x": 0, "y": 0, "z": 0}, "radius": -1}}"#,
+                "obstacle 0: the sphere radius must be positive",
+            ),
+            (
+                r#"{"Aabb": {"min": {"x": 1, "y": 0, "z": 0}, "max": {"x": 0, "y": 1, "z": 1}}}"#,
+                "obstacle 0: the box needs min to be at most max",
+            ),
+            (
+                r#"{"Plane": {"normal": {"x": 0, "y": 0, "z": 0}, "distance": 1}}"#,
+                "obstacle 0: the plane needs a non-zero normal",
+            ),
+        ] {
+            let message_found = error(&format!(
+                r#"{{{}, "arena_half_size": 10, "obstacles": [{}]}}"#,
+                camera, obstacle
+            ))
+            .contains(message);
+
+            assert!(message_found, "{}", message);
+        }
+
+        assert!(error(&format!(
+            r#"{{{}, "arena_half_size": 10, "flocks": [
+                {{"first_id": 0, "count": 3000, "min": {{"x": 0, "y": 0, "z": 0}}, "max": {{"x": 1, "y": 1, "z": 1}}}}
+            ]}}"#,
+            camera
+        ))
+        .contains("at most 2048 spaceships are supported, the scene has 3000"));
+    }
+
+    #[test]
+    fn test_spaceship_animations() {
+        let scene = Scene::parse(
+            r#"{
+                "camera": {"position": {"x": 0, "y": 0, "z": 0}, "yaw_degrees": 0, "pitch_degrees": 0},
+                "arena_half_size": 10,
+                "spaceships": [
+                    {"id": 1, "position": {"x": 0, "y": 0, "z": 0}, "animation": {"clip": "idle"}},
+                    {
+                        "id": 2, "position": {"x": 1, "y": 0, "z": 0},
+                        "animation": {"clip": "wave", "speed": -0.5, "looping": false}
+                    },
+                    {"id": 3, "position": {"x": 2, "y": 0, "z": 0}}
+                ]
+            }"#,
+        )
+        .unwrap();
+        let world = scene.build_world(1);
+        let animations = world
+            .spaceships()
+            .map(|spaceship| spaceship.animation.clone())
+            .collect::<Vec<_>>();
+
+        let idle = animations[0].as_ref().unwrap();
+
+        assert_eq!(idle.clip, "idle");
+        assert_eq!(idle.speed, 1.0);
+        assert!(idle.looping);
+
+        let wave = animations[1].as_ref().unwrap();
+
+        assert_eq!(wave.speed, -0.5);
+        assert!(!wave.looping);
+        assert!(animations[2].is_none());
+    }
+}
@@ -8,20 +8,50 @@ struct Uniforms {
 [[group(1), binding(0)]]
 var<uniform> uniforms: Uniforms;
 
+// The joint matrices of every instance of a skinned model, one instance after another
 [[block]]
+struct JointPalette {
+    joints_per_instance: u32;
+    matrices: [[stride(64)]] array<mat4x4<f32>>;
+};
+[[group(1), binding(1)]]
+var<storage> palette: [[access(read)]] JointPalette;
+
+// Matching the LIGHT_KIND constants in state.rs
+let LIGHT_KIND_POINT: u32 = 0u;
+let LIGHT_KIND_DIRECTIONAL: u32 = 1u;
+let LIGHT_KIND_SPOT: u32 = 2u;
+
 struct Light {
     position: vec3<f32>;
+    kind: u32;
     color: vec3<f32>;
+    range: f32;
+    direction: vec3<f32>;
+    inner_cone_cos: f32;
+    outer_cone_cos: f32;
+    casts_shadow: u32;
+};
+[[block]]
+struct Lights {
+    count: u32;
+    lights: [[stride(64)]] array<Light, 16>;
 };
 [[group(2), binding(0)]]
-var<uniform> light: Light;
+var<uniform> lights: Lights;
 
 struct VertexInput {
     [[location(0)]] position: vec3<f32>;
     [[location(1)]] tex_coords: vec2<f32>;
     [[location(2)]] normal: vec3<f32>;
-    [[location(3)]] tangent: vec3<f32>;
-    [[location(4)]] bitangent: vec3<f32>;
+    // xyz is the tangent, w is 1 or -1 for which way the bitangent goes from it
+    [[location(3)]] tangent: vec4<f32>;
+};
+
+// Up to four joints that move each vertex, with weights adding up to 1
+struct SkinInput {
+    [[location(12)]] joints: vec4<u32>;
+    [[location(13)]] weights: vec4<f32>;
 };
 
 struct InstanceInput {
@@ -37,15 +67,18 @@ struct InstanceInput {
 struct VertexOutput {
     [[builtin(position)]] clip_position: vec4<f32>;
     [[location(0)]] tex_coords: vec2<f32>;
-    [[location(1)]] tangent_position: vec3<f32>;
-    [[location(2)]] tangent_light_position: vec3<f32>;
-    [[location(3)]] tangent_view_position: vec3<f32>;
+    [[location(1)]] world_position: vec3<f32>;
+    [[location(2)]] world_normal: vec3<f32>;
+    [[location(3)]] world_tangent: vec3<f32>;
+    [[location(4)]] world_bitangent: vec3<f32>;
 };
 
-[[stage(vertex)]]
-fn main(
-    model: VertexInput,
+fn vertex_output(
     instance: InstanceInput,
+    position: vec3<f32>,
+    tex_coords: vec2<f32>,
+    normal: vec3<f32>,
+    tangent: vec4<f32>,
 ) -> VertexOutput {
     let model_matrix = mat4x4<f32>(
         instance.model_matrix_0,
@@ -59,29 +92,75 @@ fn main(
         instance.normal_matrix_2,
     );
 
-    // Construct the tangent matrix
-    let world_normal = normalize(normal_matrix * model.normal);
-    let world_tangent = normalize(normal_matrix * model.tangent);
-    let world_bitangent = normalize(normal_matrix * model.bitangent);
-    let tangent_matrix = transpose(mat3x3<f32>(
-        world_tangent,
-        world_bitangent,
-        world_normal,
-    ));
-
-    let world_position = model_matrix * vec4<f32>(model.position, 1.0);
+    let world_position = model_matrix * vec4<f32>(position, 1.0);
 
     var out: VertexOutput;
 
     out.clip_position = uniforms.view_proj * world_position;
-    out.tex_coords = model.tex_coords;
-    out.tangent_position = tangent_matrix * world_position.xyz;
-    out.tangent_view_position = tangent_matrix * uniforms.view_pos.xyz;
-    out.tangent_light_position = tangent_matrix * light.position;
+    out.tex_coords = tex_coords;
+    // Shading happens in world space, since there is a light per loop iteration
+    // rather than a single one to move into tangent space here
+    out.world_position = world_position.xyz;
+    out.world_normal = normalize(normal_matrix * normal);
+    out.world_tangent = normalize(normal_matrix * tangent.xyz);
+    out.world_bitangent = cross(out.world_normal, out.world_tangent) * tangent.w;
 
     return out;
 }
 
+[[stage(vertex)]]
+fn main(
+    model: VertexInput,
+    instance: InstanceInput,
+) -> VertexOutput {
+    return vertex_output(instance, model.position, model.tex_coords, model.normal, model.tangent);
+}
+
+// Moves each vertex by its joints before the instance places it in the world. Normals
+// go through the joint matrices as they are, which holds as long as joints aren't
+// scaled unevenly
+[[stage(vertex)]]
+fn main_skinned(
+    model: VertexInput,
+    skin: SkinInput,
+    instance: InstanceInput,
+    [[builtin(instance_index)]] instance_index: u32,
+) -> VertexOutput {
+    let total_weight = skin.weights.x + skin.weights.y + skin.weights.z + skin.weights.w;
+
+    // Vertices that aren't weighted to any joint stay where they were modelled
+    if (total_weight == 0.0) {
+        return vertex_output(instance, model.position, model.tex_coords, model.normal, model.tangent);
+    }
+
+    let first = instance_index * palette.joints_per_instance;
+    let position = vec4<f32>(model.position, 1.0);
+    let normal = vec4<f32>(model.normal, 0.0);
+    let tangent = vec4<f32>(model.tangent.xyz, 0.0);
+
+    var skinned_position: vec4<f32> = vec4<f32>(0.0);
+    var skinned_normal: vec4<f32> = vec4<f32>(0.0);
+    var skinned_tangent: vec4<f32> = vec4<f32>(0.0);
+
+    for (var i: u32 = 0u; i < 4u; i = i + 1u) {
+        let joint = first + skin.joints[i];
+        let joint_matrix = palette.matrices[joint];
+        let weight = skin.weights[i];
+
+        skinned_position = skinned_position + joint_matrix * position * weight;
+        skinned_normal = skinned_normal + joint_matrix * normal * weight;
+        skinned_tangent = skinned_tangent + joint_matrix * tangent * weight;
+    }
+
+    return vertex_output(
+        instance,
+        skinned_position.xyz,
+        model.tex_coords,
+        skinned_normal.xyz,
+        vec4<f32>(skinned_tangent.xyz, model.tangent.w),
+    );
+}
+
 // Fragment shader
 
 [[group(0), binding(0)]]
@@ -93,28 +172,177 @@ var t_normal: texture_2d<f32>;
 [[group(0), binding(3)]]
 var s_normal: sampler;
 
+// From the model's MTL file
+[[block]]
+struct Material {
+    base_color: vec4<f32>;
+    specular: vec3<f32>;
+    shininess: f32;
+    emissive: vec3<f32>;
+};
+[[group(0), binding(4)]]
+var<uniform> material: Material;
+
+[[block]]
+struct Shadows {
+    directional_view_proj: mat4x4<f32>;
+    point_near: f32;
+    point_far: f32;
+    map_size: f32;
+};
+[[group(3), binding(0)]]
+var<uniform> shadows: Shadows;
+[[group(3), binding(1)]]
+var t_point_shadow: texture_depth_cube;
+[[group(3), binding(2)]]
+var t_directional_shadow: texture_depth_2d;
+[[group(3), binding(3)]]
+var s_shadow: sampler_comparison;
+
+// Smoothly down to nothing at range, rather than an inverse square that never quite gets there
+fn range_attenuation(light_distance: f32, range: f32) -> f32 {
+    let ratio = light_distance / range;
+    let falloff = clamp(1.0 - ratio * ratio * ratio * ratio, 0.0, 1.0);
+
+    return falloff * falloff;
+}
+
+// How much of a point light reaches a position past whatever is in the way,
+// from 0 in full shadow to 1. Averages a 3x3x3 block of lookups (PCF) for soft edges
+fn point_shadow(light_position: vec3<f32>, world_position: vec3<f32>) -> f32 {
+    let from_light = world_position - light_position;
+    let abs_from_light = abs(from_light);
+    // the cube face a direction lands on is the one of its largest component,
+    // and that component is the distance the face's projection saw
+    let face_distance = max(abs_from_light.x, max(abs_from_light.y, abs_from_light.z));
+    let near_plane = shadows.point_near;
+    let far_plane = shadows.point_far;
+
+    if (face_distance >= far_plane) {
+        return 1.0;
+    }
+
+    let depth = far_plane / (far_plane - near_plane)
+        - far_plane * near_plane / ((far_plane - near_plane) * face_distance);
+    // a cube face is twice the distance wide where the position is
+    let texel = 2.0 * face_distance / shadows.map_size;
+    var lit: f32 = 0.0;
+
+    for (var x: i32 = -1; x <= 1; x = x + 1) {
+        for (var y: i32 = -1; y <= 1; y = y + 1) {
+            for (var z: i32 = -1; z <= 1; z = z + 1) {
+                let offset = vec3<f32>(f32(x), f32(y), f32(z)) * texel;
+
+                lit = lit + textureSampleCompareLevel(t_point_shadow, s_shadow, from_light + offset, depth);
+            }
+        }
+    }
+
+    return lit / 27.0;
+}
+
+// Like point_shadow, for the directional light with a 3x3 block of lookups
+fn directional_shadow(world_position: vec3<f32>) -> f32 {
+    let light_space = shadows.directional_view_proj * vec4<f32>(world_position, 1.0);
+    let ndc = light_space.xyz / light_space.w;
+
+    // nothing outside the map is known to be in the way
+    if (ndc.x < -1.0 || ndc.x > 1.0 || ndc.y < -1.0 || ndc.y > 1.0 || ndc.z > 1.0) {
+        return 1.0;
+    }
+
+    // texture coordinates run down from the top, unlike clip space
+    let uv = vec2<f32>(ndc.x * 0.5 + 0.5, -ndc.y * 0.5 + 0.5);
+    let texel = 1.0 / shadows.map_size;
+    var lit: f32 = 0.0;
+
+    for (var x: i32 = -1; x <= 1; x = x + 1) {
+        for (var y: i32 = -1; y <= 1; y = y + 1) {
+            let offset = vec2<f32>(f32(x), f32(y)) * texel;
+
+            lit = lit + textureSampleCompareLevel(t_directional_shadow, s_shadow, uv + offset, ndc.z);
+        }
+    }
+
+    return lit / 9.0;
+}
+
+// 1 within the inner cone, fading to 0 at the outer cone
+fn spot_cone_factor(light: Light, light_dir: vec3<f32>) -> f32 {
+    let cos_angle = dot(-light_dir, light.direction);
+    let fade_width = max(light.inner_cone_cos - light.outer_cone_cos, 0.0001);
+
+    return clamp((cos_angle - light.outer_cone_cos) / fade_width, 0.0, 1.0);
+}
+
 [[stage(fragment)]]
 fn main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
-    let object_color: vec4<f32> = textureSample(t_diffuse, s_diffuse, in.tex_coords);
+    let object_color = textureSample(t_diffuse, s_diffuse, in.tex_coords) * material.base_color;
     let object_normal: vec4<f32> = textureSample(t_normal, s_normal, in.tex_coords);
 
+    // Bring the normal map sample from tangent space into world space
+    let tangent_matrix = mat3x3<f32>(
+        normalize(in.world_tangent),
+        normalize(in.world_bitangent),
+        normalize(in.world_normal),
+    );
+
+    let tangent_normal = object_normal.xyz * 2.0 - 1.0;
+    let world_normal = normalize(tangent_matrix * tangent_normal);
+    let view_dir = normalize(uniforms.view_pos.xyz - in.world_position);
+
     let ambient_strength = 0.2;
-    let ambient_color = light.color * ambient_strength;
+    // the surface's own color takes the diffuse light, the specular color the highlights
+    var diffuse_light: vec3<f32> = vec3<f32>(0.0, 0.0, 0.0);
+    var specular_light: vec3<f32> = vec3<f32>(0.0, 0.0, 0.0);
+
+    for (var i: u32 = 0u; i < lights.count; i = i + 1u) {
+        let light = lights.lights[i];
+        var light_dir: vec3<f32> = -light.direction;
+        var attenuation: f32 = 1.0;
+        var cone_factor: f32 = 1.0;
+
+        if (light.kind != LIGHT_KIND_DIRECTIONAL) {
+            let to_light = light.position - in.world_position;
+            let light_distance = length(to_light);
+
+            light_dir = to_light / max(light_distance, 0.0001);
+            attenuation = range_attenuation(light_distance, light.range);
+        }
+
+        if (light.kind == LIGHT_KIND_SPOT) {
+            cone_factor = spot_cone_factor(light, light_dir);
+        }
+
+        var shadow: f32 = 1.0;
+
+        if (light.casts_shadow != 0u) {
+            if (light.kind == LIGHT_KIND_DIRECTIONAL) {
+                shadow = directional_shadow(in.world_position);
+            } else {
+                shadow = point_shadow(light.position, in.world_position);
+            }
+        }
+
+        let half_dir = normalize(view_dir + light_dir);
+
+        let ambient_color = light.color * ambient_strength;
 
-    // how much diffuse light does the object reflect, like a "matte -> metal spectrum"
-    let reflect_factor = 0.66;
-    let tangent_normal = (object_normal.xyz * 2.0 - 1.0) * reflect_factor;
-    let light_dir = normalize(in.tangent_light_position - in.tangent_position);
-    let view_dir = normalize(in.tangent_view_position - in.tangent_position);
-    let half_dir = normalize(view_dir + light_dir);
+        let diffuse_strength = max(dot(world_normal, light_dir), 0.0);
+        let diffuse_color = light.color * diffuse_strength;
 
-    let diffuse_strength = max(dot(tangent_normal, light_dir), 0.0);
-    let diffuse_color = light.color * diffuse_strength;
+        let specular_strength = pow(max(dot(world_normal, half_dir), 0.0), material.shininess);
+        let specular_color = specular_strength * light.color;
+        let visibility = cone_factor * shadow * attenuation;
 
-    let specular_strength = pow(max(dot(tangent_normal, half_dir), 0.0), 32.0);
-    let specular_color = specular_strength * light.color;
+        // the surroundings pick up some of a light within its range, even outside a spot's cone
+        diffuse_light = diffuse_light + ambient_color * attenuation + diffuse_color * visibility;
+        specular_light = specular_light + specular_color * visibility;
+    }
 
-    let result = (ambient_color + diffuse_color + specular_color) * object_color.xyz;
+    let result = diffuse_light * object_color.xyz
+        + specular_light * material.specular
+        + material.emissive;
 
     return vec4<f32>(result, object_color.a);
 }
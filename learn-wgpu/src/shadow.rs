@@ -0,0 +1,521 @@
+use cgmath::{prelude::*, Deg, Matrix4, Point3, Vector3};
+use std::ops::Range;
+use wgpu::util::DeviceExt;
+
+use crate::camera::OPENGL_TO_WGPU_MATRIX;
+use crate::entity::{Light, LightKind, DEFAULT_LIGHT_RANGE};
+use crate::model::{self, DrawShadow};
+use crate::scene::ShadowsDescription;
+use crate::texture;
+use crate::world::MAX_LIGHTS;
+
+// Nothing closer to a point light than this casts a shadow
+pub const SHADOW_NEAR: f32 = 0.1;
+
+// Where each cube map layer looks from the light, and which way is up on it
+const CUBE_FACES: [(Vector3<f32>, Vector3<f32>); 6] = [
+    (Vector3::new(1.0, 0.0, 0.0), Vector3::new(0.0, -1.0, 0.0)),
+    (Vector3::new(-1.0, 0.0, 0.0), Vector3::new(0.0, -1.0, 0.0)),
+    (Vector3::new(0.0, 1.0, 0.0), Vector3::new(0.0, 0.0, 1.0)),
+    (Vector3::new(0.0, -1.0, 0.0), Vector3::new(0.0, 0.0, -1.0)),
+    (Vector3::new(0.0, 0.0, 1.0), Vector3::new(0.0, -1.0, 0.0)),
+    (Vector3::new(0.0, 0.0, -1.0), Vector3::new(0.0, -1.0, 0.0)),
+];
+
+// Which lights render a shadow map, as indices into the world's lights
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShadowCasters {
+    // the first point or spot light, into the cube map
+    pub point: Option<usize>,
+    // the first directional light
+    pub directional: Option<usize>,
+}
+
+impl ShadowCasters {
+    pub fn find(lights: &[Light]) -> Self {
+        let lights = &lights[..lights.len().min(MAX_LIGHTS)];
+
+        ShadowCasters {
+            point: lights.iter().position(|light| !light.is_directional()),
+            directional: lights.iter().position(|light| light.is_directional()),
+        }
+    }
+
+    pub fn contains(&self, index: usize) -> bool {
+        self.point == Some(index) || self.directional == Some(index)
+    }
+}
+
+// The view projection of every cube face, in layer order. The cube map lookup
+// expects its faces upside down compared to how wgpu renders them, hence the flip
+pub fn cube_face_view_projs(position: Vector3<f32>, far: f32) -> [Matrix4<f32>; 6] {
+    let flip_y = Matrix4::from_nonuniform_scale(1.0, -1.0, 1.0);
+    let projection =
+        flip_y * OPENGL_TO_WGPU_MATRIX * cgmath::perspective(Deg(90.0), 1.0, SHADOW_NEAR, far);
+
+    CUBE_FACES.map(|(direction, up)| {
+        projection * Matrix4::look_to_rh(Point3::from_vec(position), direction, up)
+    })
+}
+
+// Looks along the light from outside a box of the given half size around the origin
+pub fn directional_view_proj(direction: Vector3<f32>, half_size: f32) -> Matrix4<f32> {
+    let direction = direction.normalize();
+    let up = if direction.y.abs() > 0.99 {
+        Vector3::unit_z()
+    } else {
+        Vector3::unit_y()
+    };
+    let eye = Point3::from_vec(-direction * half_size * 2.0);
+    let projection = cgmath::ortho(
+        -half_size,
+        half_size,
+        -half_size,
+        half_size,
+        0.0,
+        half_size * 4.0,
+    );
+
+    OPENGL_TO_WGPU_MATRIX * projection * Matrix4::look_to_rh(eye, direction, up)
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct ShadowPassRaw {
+    view_proj: [[f32; 4]; 4],
+}
+
+// What the main shader needs to look up the shadow maps
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct ShadowsRaw {
+    pub directional_view_proj: [[f32; 4]; 4],
+    pub point_near: f32,
+    pub point_far: f32,
+    pub map_size: f32,
+    _padding: u32,
+}
+
+pub struct ShadowMaps {
+    settings: ShadowsDescription,
+    casters: ShadowCasters,
+    pipeline: wgpu::RenderPipeline,
+    // for models with a skeleton, which also reads the joint palette
+    skinned_pipeline: wgpu::RenderPipeline,
+    // a view projection per cube face, then the directional one
+    pass_buffers: Vec<wgpu::Buffer>,
+    pass_bind_groups: Vec<wgpu::BindGroup>,
+    // owns the texture behind the face views
+    _point_map: texture::Texture,
+    point_face_views: Vec<wgpu::TextureView>,
+    directional_map: texture::Texture,
+    buffer: wgpu::Buffer,
+    // for the main pipeline to read the shadow maps with
+    pub bind_group_layout: wgpu::BindGroupLayout,
+    pub bind_group: wgpu::BindGroup,
+}
+
+impl ShadowMaps {
+    pub fn new(
+        device: &wgpu::Device,
+        settings: ShadowsDescription,
+        vertex_layouts: &[wgpu::VertexBufferLayout],
+        skinned_vertex_layouts: &[wgpu::VertexBufferLayout],
+        palette_bind_group_layout: &wgpu::BindGroupLayout,
+    ) -> Self {
+        let point_map =
+            texture::Texture::create_shadow_map(device, settings.map_size, 6, "point_shadow_map");
+        let point_face_views = (0..6).map(|face| point_map.layer_view(face)).collect();
+        let directional_map = texture::Texture::create_shadow_map(
+            device,
+            settings.map_size,
+            1,
+            "directional_shadow_map",
+        );
+
+        let pass_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                entries: &[wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::VERTEX,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                }],
+                label: Some("shadow_pass_bind_group_layout"),
+            });
+
+        let pass_buffers = (0..7)
+            .map(|_| {
+                device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some("Shadow Pass Buffer"),
+                    contents: bytemuck::cast_slice(&[ShadowPassRaw {
+                        view_proj: Matrix4::identity().into(),
+                    }]),
+                    usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+                })
+            })
+            .collect::<Vec<_>>();
+        let pass_bind_groups = pass_buffers
+            .iter()
+            .map(|buffer| {
+                device.create_bind_group(&wgpu::BindGroupDescriptor {
+                    layout: &pass_bind_group_layout,
+                    entries: &[wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: buffer.as_entire_binding(),
+                    }],
+                    label: Some("shadow_pass_bind_group"),
+                })
+            })
+            .collect();
+
+        let shader = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+            label: Some("Shadow Shader"),
+            flags: wgpu::ShaderFlags::all(),
+            source: wgpu::ShaderSource::Wgsl(include_str!("shadow.wgsl").into()),
+        });
+        let create_pipeline =
+            |bind_group_layouts: &[&wgpu::BindGroupLayout],
+             entry_point: &str,
+             vertex_layouts: &[wgpu::VertexBufferLayout]| {
+                let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                    label: Some("Shadow Pipeline Layout"),
+                    bind_group_layouts,
+                    push_constant_ranges: &[],
+                });
+
+                device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                    label: Some("Shadow Pipeline"),
+                    layout: Some(&layout),
+                    vertex: wgpu::VertexState {
+                        module: &shader,
+                        entry_point,
+                        buffers: vertex_layouts,
+                    },
+                    fragment: None,
+                    primitive: wgpu::PrimitiveState {
+                        topology: wgpu::PrimitiveTopology::TriangleList,
+                        strip_index_format: None,
+                        front_face: wgpu::FrontFace::Ccw,
+                        // the flipped cube faces wind the other way, so both sides are drawn
+                        cull_mode: None,
+                        polygon_mode: wgpu::PolygonMode::Fill,
+                        clamp_depth: false,
+                        conservative: false,
+                    },
+                    depth_stencil: Some(wgpu::DepthStencilState {
+                        format: texture::Texture::DEPTH_FORMAT,
+                        depth_write_enabled: true,
+                        depth_compare: wgpu::CompareFunction::LessEqual,
+                        stencil: wgpu::StencilState::default(),
+                        bias: wgpu::DepthBiasState {
+                            constant: settings.constant_bias,
+                            slope_scale: settings.slope_scale_bias,
+                            clamp: 0.0,
+                        },
+                    }),
+                    multisample: wgpu::MultisampleState {
+                        count: 1,
+                        mask: !0,
+                        alpha_to_coverage_enabled: false,
+                    },
+                })
+            };
+        let pipeline = create_pipeline(&[&pass_bind_group_layout], "main", vertex_layouts);
+        let skinned_pipeline = create_pipeline(
+            &[&pass_bind_group_layout, palette_bind_group_layout],
+            "main_skinned",
+            skinned_vertex_layouts,
+        );
+
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Shadows Buffer"),
+            contents: bytemuck::cast_slice(&[ShadowsRaw {
+                directional_view_proj: Matrix4::identity().into(),
+                point_near: SHADOW_NEAR,
+                point_far: DEFAULT_LIGHT_RANGE,
+                map_size: settings.map_size as f32,
+                _padding: 0,
+            }]),
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+        });
+
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        sample_type: wgpu::TextureSampleType::Depth,
+                        view_dimension: wgpu::TextureViewDimension::Cube,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        sample_type: wgpu::TextureSampleType::Depth,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler {
+                        comparison: true,
+                        filtering: true,
+                    },
+                    count: None,
+                },
+            ],
+            label: Some("shadow_bind_group_layout"),
+        });
+
+        // both maps compare the same way, so one sampler serves them
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&point_map.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(&directional_map.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::Sampler(&point_map.sampler),
+                },
+            ],
+            label: Some("shadow_bind_group"),
+        });
+
+        ShadowMaps {
+            settings,
+            casters: ShadowCasters {
+                point: None,
+                directional: None,
+            },
+            pipeline,
+            skinned_pipeline,
+            pass_buffers,
+            pass_bind_groups,
+            _point_map: point_map,
+            point_face_views,
+            directional_map,
+            buffer,
+            bind_group_layout,
+            bind_group,
+        }
+    }
+
+    pub fn settings(&self) -> ShadowsDescription {
+        self.settings
+    }
+
+    // Point the shadow passes at where the casting lights are this frame
+    pub fn update(&mut self, queue: &wgpu::Queue, lights: &[Light], alpha: f32) {
+        self.casters = ShadowCasters::find(lights);
+
+        let mut raw = ShadowsRaw {
+            directional_view_proj: Matrix4::identity().into(),
+            point_near: SHADOW_NEAR,
+            point_far: DEFAULT_LIGHT_RANGE,
+            map_size: self.settings.map_size as f32,
+            _padding: 0,
+        };
+
+        if let Some(index) = self.casters.point {
+            let light = &lights[index];
+            let far = match light.kind {
+                LightKind::Point { range } | LightKind::Spot { range, .. } => range,
+                LightKind::Directional { .. } => DEFAULT_LIGHT_RANGE,
+            };
+            let view_projs = cube_face_view_projs(light.interpolated_position(alpha), far);
+
+            for (buffer, view_proj) in self.pass_buffers.iter().zip(view_projs.iter()) {
+                queue.write_buffer(
+                    buffer,
+                    0,
+                    bytemuck::cast_slice(&[ShadowPassRaw {
+                        view_proj: (*view_proj).into(),
+                    }]),
+                );
+            }
+
+            raw.point_far = far;
+        }
+
+        if let Some(index) = self.casters.directional {
+            if let LightKind::Directional { direction } = lights[index].kind {
+                let view_proj =
+                    directional_view_proj(direction, self.settings.directional_half_size);
+
+                queue.write_buffer(
+                    &self.pass_buffers[6],
+                    0,
+                    bytemuck::cast_slice(&[ShadowPassRaw {
+                        view_proj: view_proj.into(),
+                    }]),
+                );
+                raw.directional_view_proj = view_proj.into();
+            }
+        }
+
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&[raw]));
+    }
+
+    // Render the depth of every instance into the maps of the lights that cast shadows
+    pub fn render(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        model: &model::Model,
+        instance_buffer: &wgpu::Buffer,
+        instances: Range<u32>,
+        palette_bind_group: &wgpu::BindGroup,
+    ) {
+        let mut passes = Vec::new();
+
+        if self.casters.point.is_some() {
+            passes.extend(
+                self.point_face_views
+                    .iter()
+                    .zip(self.pass_bind_groups.iter()),
+            );
+        }
+
+        if self.casters.directional.is_some() {
+            passes.push((&self.directional_map.view, &self.pass_bind_groups[6]));
+        }
+
+        for (view, bind_group) in passes {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Shadow Pass"),
+                color_attachments: &[],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(1.0),
+                        store: true,
+                    }),
+                    stencil_ops: None,
+                }),
+            });
+
+            if model.skeleton.is_some() {
+                render_pass.set_pipeline(&self.skinned_pipeline);
+                render_pass.set_bind_group(1, palette_bind_group, &[]);
+            } else {
+                render_pass.set_pipeline(&self.pipeline);
+            }
+
+            render_pass.set_vertex_buffer(1, instance_buffer.slice(..));
+            render_pass.draw_shadow_model_instanced(model, instances.clone(), bind_group);
+        }
+    }
+}
+
+//
+// Tests
+//
+
+#[cfg(test)]
+mod tests {
+    use crate::entity::{Light, LightKind};
+    use crate::shadow::{cube_face_view_projs, directional_view_proj, ShadowCasters, SHADOW_NEAR};
+    use cgmath::{prelude::*, Vector3, Vector4};
+
+    fn project(view_proj: cgmath::Matrix4<f32>, point: Vector3<f32>) -> Vector3<f32> {
+        let clip = view_proj * point.extend(1.0);
+
+        clip.truncate() / clip.w
+    }
+
+    #[test]
+    fn test_cube_faces_match_the_shader_depth() {
+        let light = Vector3::new(1.0, 2.0, 3.0);
+        let far = 20.0;
+        let view_projs = cube_face_view_projs(light, far);
+        let directions = [
+            Vector3::unit_x(),
+            -Vector3::unit_x(),
+            Vector3::unit_y(),
+            -Vector3::unit_y(),
+            Vector3::unit_z(),
+            -Vector3::unit_z(),
+        ];
+
+        for (view_proj, direction) in view_projs.iter().zip(directions.iter()) {
+            let distance = 7.0;
+            let ndc = project(*view_proj, light + direction * distance);
+
+            // Straight ahead of each face lands in its middle
+            assert!(ndc.x.abs() < 0.0001 && ndc.y.abs() < 0.0001);
+
+            // At the depth that shader.wgsl works out from the distance alone
+            let expected =
+                far / (far - SHADOW_NEAR) - far * SHADOW_NEAR / ((far - SHADOW_NEAR) * distance);
+            assert!((ndc.z - expected).abs() < 0.0001);
+        }
+
+        // The cube map lookup puts +y towards the top of the +x face, i.e. at a smaller v
+        let ndc = project(view_projs[0], light + Vector3::new(5.0, 1.0, 0.0));
+        assert!(ndc.y > 0.0);
+    }
+
+    #[test]
+    fn test_directional_covers_the_box() {
+        let view_proj = directional_view_proj(Vector3::new(-1.0, -0.5, -0.3), 10.0);
+
+        for corner in [Vector3::new(-5.0, -5.0, -5.0), Vector3::new(5.0, 5.0, 5.0)] {
+            let ndc = project(view_proj, corner);
+
+            assert!(ndc.x.abs() <= 1.0 && ndc.y.abs() <= 1.0);
+            assert!((0.0..=1.0).contains(&ndc.z));
+        }
+
+        // Straight down works too, despite the usual up vector
+        let down =
+            directional_view_proj(-Vector3::unit_y(), 10.0) * Vector4::new(0.0, 0.0, 0.0, 1.0);
+        assert!(down.x.is_finite() && down.y.is_finite());
+    }
+
+    #[test]
+    fn test_shadow_casters() {
+        let mut sun = Light::new(Vector3::zero(), [1.0; 3]);
+
+        sun.kind = LightKind::Directional {
+            direction: -Vector3::unit_y(),
+        };
+
+        let lights = vec![sun.clone(), Light::new(Vector3::zero(), [1.0; 3]), sun];
+        let casters = ShadowCasters::find(&lights);
+
+        assert_eq!(casters.point, Some(1));
+        assert_eq!(casters.directional, Some(0));
+        assert!(!casters.contains(2));
+    }
+}
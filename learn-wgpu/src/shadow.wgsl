@@ -0,0 +1,81 @@
+// Vertex shader
+
+[[block]]
+struct ShadowPass {
+    view_proj: mat4x4<f32>;
+};
+[[group(0), binding(0)]]
+var<uniform> shadow_pass: ShadowPass;
+
+// Shared with shader.wgsl, through the same bind group
+[[block]]
+struct JointPalette {
+    joints_per_instance: u32;
+    matrices: [[stride(64)]] array<mat4x4<f32>>;
+};
+[[group(1), binding(1)]]
+var<storage> palette: [[access(read)]] JointPalette;
+
+struct VertexInput {
+    [[location(0)]] position: vec3<f32>;
+};
+
+struct SkinInput {
+    [[location(12)]] joints: vec4<u32>;
+    [[location(13)]] weights: vec4<f32>;
+};
+
+struct InstanceInput {
+    [[location(5)]] model_matrix_0: vec4<f32>;
+    [[location(6)]] model_matrix_1: vec4<f32>;
+    [[location(7)]] model_matrix_2: vec4<f32>;
+    [[location(8)]] model_matrix_3: vec4<f32>;
+};
+
+// Only depth is written, so there is no fragment shader
+[[stage(vertex)]]
+fn main(
+    model: VertexInput,
+    instance: InstanceInput,
+) -> [[builtin(position)]] vec4<f32> {
+    let model_matrix = mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+
+    return shadow_pass.view_proj * model_matrix * vec4<f32>(model.position, 1.0);
+}
+
+[[stage(vertex)]]
+fn main_skinned(
+    model: VertexInput,
+    skin: SkinInput,
+    instance: InstanceInput,
+    [[builtin(instance_index)]] instance_index: u32,
+) -> [[builtin(position)]] vec4<f32> {
+    let model_matrix = mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+    let total_weight = skin.weights.x + skin.weights.y + skin.weights.z + skin.weights.w;
+    let first = instance_index * palette.joints_per_instance;
+    let position = vec4<f32>(model.position, 1.0);
+
+    var skinned_position: vec4<f32> = position;
+
+    if (total_weight > 0.0) {
+        skinned_position = vec4<f32>(0.0);
+
+        for (var i: u32 = 0u; i < 4u; i = i + 1u) {
+            let joint = first + skin.joints[i];
+
+            skinned_position = skinned_position + palette.matrices[joint] * position * skin.weights[i];
+        }
+    }
+
+    return shadow_pass.view_proj * model_matrix * skinned_position;
+}
@@ -0,0 +1,111 @@
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::scene::{self, SceneView};
+use crate::world::World;
+
+// A running world captured mid-simulation, to be picked up again later
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub view: SceneView,
+    pub world: World,
+}
+
+impl Snapshot {
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("Could not open snapshot {}", path.display()))?;
+
+        let snapshot: Snapshot = serde_json::from_reader(std::io::BufReader::new(file))
+            .with_context(|| format!("Could not read snapshot {}", path.display()))?;
+
+        // held to the same checks as a scene, since it can be edited by hand just the same
+        scene::validate_view(&snapshot.view)
+            .and_then(|_| scene::validate_world(&snapshot.world))
+            .with_context(|| format!("Invalid snapshot {}", path.display()))?;
+
+        Ok(snapshot)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        let file = std::fs::File::create(path)
+            .with_context(|| format!("Could not create snapshot {}", path.display()))?;
+
+        serde_json::to_writer_pretty(std::io::BufWriter::new(file), self)
+            .with_context(|| format!("Could not write snapshot {}", path.display()))
+    }
+}
+
+//
+// Tests
+//
+
+#[cfg(test)]
+mod tests {
+    use crate::obstacle::Obstacle;
+    use crate::scene::{default_scene_path, Scene};
+    use crate::snapshot::Snapshot;
+    use cgmath::Vector3;
+    use std::time::Duration;
+
+    #[test]
+    fn test_snapshot_resumes_the_simulation() {
+        let scene = Scene::load(default_scene_path()).unwrap();
+        let mut world = scene.build_world(21);
+        let dt = Duration::from_millis(16);
+
+        for _ in 0..100 {
+            world.step(dt);
+        }
+
+        let path = std::env::temp_dir().join("learn-wgpu-test-snapshot.json");
+        Snapshot {
+            view: scene.view(),
+            world: world.clone(),
+        }
+        .save(&path)
+        .unwrap();
+        let mut restored = Snapshot::load(&path).unwrap().world;
+        std::fs::remove_file(&path).unwrap();
+
+        // Mid-flight state like velocities, chase targets and the RNG all survive the trip
+        assert_eq!(restored.state_hash(), world.state_hash());
+
+        for _ in 0..100 {
+            world.step(dt);
+            restored.step(dt);
+        }
+
+        assert_eq!(restored.state_hash(), world.state_hash());
+    }
+
+    #[test]
+    fn test_invalid_snapshots_are_rejected() {
+        let scene = Scene::load(default_scene_path()).unwrap();
+        let mut world = scene.build_world(21);
+
+        world.set_obstacles(vec![Obstacle::Sphere {
+            center: Vector3::new(0.0, 0.0, 0.0),
+            radius: -1.0,
+        }]);
+
+        let path = std::env::temp_dir().join("learn-wgpu-test-invalid-snapshot.json");
+        Snapshot {
+            view: scene.view(),
+            world,
+        }
+        .save(&path)
+        .unwrap();
+        let error = format!("{:#}", Snapshot::load(&path).err().unwrap());
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(
+            error.contains("obstacle 0: the sphere radius must be positive"),
+            "{}",
+            error
+        );
+    }
+}
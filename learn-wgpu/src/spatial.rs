@@ -0,0 +1,191 @@
+use cgmath::{InnerSpace, Vector3};
+use std::collections::HashMap;
+
+type Cell = (i32, i32, i32);
+
+// A uniform grid that buckets entity ids by position. Rebuilt every update,
+// so it only needs to support inserting and querying
+#[derive(Clone)]
+pub struct SpatialGrid {
+    cell_size: f32,
+    cells: HashMap<Cell, Vec<u16>>,
+    positions: HashMap<u16, Vector3<f32>>,
+    min_cell: Cell,
+    max_cell: Cell,
+}
+
+impl SpatialGrid {
+    pub fn new(cell_size: f32) -> Self {
+        SpatialGrid {
+            cell_size,
+            cells: HashMap::new(),
+            positions: HashMap::new(),
+            min_cell: (0, 0, 0),
+            max_cell: (0, 0, 0),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.cells.clear();
+        self.positions.clear();
+    }
+
+    pub fn insert(&mut self, id: u16, position: Vector3<f32>) {
+        let cell = self.cell_of(position);
+
+        if self.positions.is_empty() {
+            self.min_cell = cell;
+            self.max_cell = cell;
+        } else {
+            self.min_cell = (
+                self.min_cell.0.min(cell.0),
+                self.min_cell.1.min(cell.1),
+                self.min_cell.2.min(cell.2),
+            );
+            self.max_cell = (
+                self.max_cell.0.max(cell.0),
+                self.max_cell.1.max(cell.1),
+                self.max_cell.2.max(cell.2),
+            );
+        }
+
+        self.cells.entry(cell).or_default().push(id);
+        self.positions.insert(id, position);
+    }
+
+    // Every id within the radius, sorted by id so that callers iterate in a stable order
+    pub fn query_radius(&self, position: Vector3<f32>, radius: f32) -> Vec<u16> {
+        let (x0, y0, z0) = self.cell_of(position - Vector3::new(radius, radius, radius));
+        let (x1, y1, z1) = self.cell_of(position + Vector3::new(radius, radius, radius));
+        let mut result = Vec::new();
+
+        for x in x0..=x1 {
+            for y in y0..=y1 {
+                for z in z0..=z1 {
+                    let ids = match self.cells.get(&(x, y, z)) {
+                        Some(ids) => ids,
+                        None => continue,
+                    };
+
+                    result.extend(
+                        ids.iter()
+                            .filter(|id| (self.positions[id] - position).magnitude() <= radius),
+                    );
+                }
+            }
+        }
+
+        result.sort_unstable();
+        result
+    }
+
+    // The k closest ids, nearest first
+    pub fn k_nearest(&self, position: Vector3<f32>, k: usize) -> Vec<u16> {
+        if k == 0 || self.positions.is_empty() {
+            return Vec::new();
+        }
+
+        let center = self.cell_of(position);
+        // No occupied cell lies further away than this many rings
+        let max_ring = [
+            (center.0 - self.min_cell.0).abs(),
+            (center.0 - self.max_cell.0).abs(),
+            (center.1 - self.min_cell.1).abs(),
+            (center.1 - self.max_cell.1).abs(),
+            (center.2 - self.min_cell.2).abs(),
+            (center.2 - self.max_cell.2).abs(),
+        ]
+        .iter()
+        .copied()
+        .max()
+        .unwrap_or(0);
+        let mut candidates: Vec<(f32, u16)> = Vec::new();
+
+        for ring in 0..=max_ring {
+            for x in center.0 - ring..=center.0 + ring {
+                for y in center.1 - ring..=center.1 + ring {
+                    for z in center.2 - ring..=center.2 + ring {
+                        let is_on_ring = (x - center.0).abs() == ring
+                            || (y - center.1).abs() == ring
+                            || (z - center.2).abs() == ring;
+
+                        if !is_on_ring {
+                            continue;
+                        }
+
+                        if let Some(ids) = self.cells.get(&(x, y, z)) {
+                            candidates.extend(
+                                ids.iter()
+                                    .map(|id| ((self.positions[id] - position).magnitude(), *id)),
+                            );
+                        }
+                    }
+                }
+            }
+
+            candidates.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+
+            // Anything in the unvisited rings is at least this far away
+            let searched_distance = ring as f32 * self.cell_size;
+
+            if candidates.len() >= k && candidates[k - 1].0 <= searched_distance {
+                break;
+            }
+        }
+
+        candidates.iter().take(k).map(|(_, id)| *id).collect()
+    }
+
+    fn cell_of(&self, position: Vector3<f32>) -> Cell {
+        (
+            (position.x / self.cell_size).floor() as i32,
+            (position.y / self.cell_size).floor() as i32,
+            (position.z / self.cell_size).floor() as i32,
+        )
+    }
+}
+
+//
+// Tests
+//
+
+#[cfg(test)]
+mod tests {
+    use crate::spatial::SpatialGrid;
+    use cgmath::Vector3;
+
+    fn grid_with_line_of_entities() -> SpatialGrid {
+        let mut grid = SpatialGrid::new(2.0);
+
+        for id in 0..10 {
+            grid.insert(id, Vector3::new(id as f32 * 1.5, 0.0, 0.0));
+        }
+
+        grid
+    }
+
+    #[test]
+    fn test_query_radius() {
+        let grid = grid_with_line_of_entities();
+
+        assert_eq!(
+            grid.query_radius(Vector3::new(3.0, 0.0, 0.0), 1.6),
+            vec![1, 2, 3]
+        );
+        assert!(grid
+            .query_radius(Vector3::new(0.0, 10.0, 0.0), 1.0)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_k_nearest() {
+        let grid = grid_with_line_of_entities();
+
+        assert_eq!(
+            grid.k_nearest(Vector3::new(7.0, 0.0, 0.0), 3),
+            vec![5, 4, 6]
+        );
+        assert_eq!(grid.k_nearest(Vector3::new(-50.0, 0.0, 0.0), 2), vec![0, 1]);
+        assert_eq!(grid.k_nearest(Vector3::new(0.0, 0.0, 0.0), 20).len(), 10);
+    }
+}
@@ -1,15 +1,24 @@
 use cgmath::prelude::*;
 use model::{DrawLight, DrawModel, Vertex};
-use rand::{prelude::IteratorRandom, thread_rng};
-use std::collections::HashMap;
 use wgpu::util::DeviceExt;
 use winit::{event::*, window::Window};
 
+use crate::animation::clip_time;
 use crate::camera;
-use crate::entity::{Light, Spaceship, SpaceshipState};
+use crate::entity::{Light, LightKind, Transform};
 use crate::model;
-use crate::steering::{self, DummyKinematic, Kinematic};
+use crate::recording::Replay;
+use crate::scene::{self, CameraDescription, ModelsDescription, SceneView};
+use crate::shadow::{ShadowCasters, ShadowMaps};
+use crate::snapshot::Snapshot;
 use crate::texture;
+use crate::timestep::FixedTimestep;
+use crate::world::{World, MAX_LIGHTS, MAX_SPACESHIPS};
+
+// The joint palette starts with the number of joints per instance, padded to where
+// the shaders expect the first matrix
+const PALETTE_HEADER_SIZE: usize = 16;
+const PALETTE_MATRIX_SIZE: usize = 64;
 
 const CLEAR_COLOR: wgpu::Color = wgpu::Color {
     r: 0.0,
@@ -18,8 +27,6 @@ const CLEAR_COLOR: wgpu::Color = wgpu::Color {
     a: 1.0,
 };
 
-const CHASE_STOP_DISTANCE: f32 = 2.0;
-
 // We need this for Rust to store our data correctly for the shaders
 #[repr(C)]
 // This is so we can store this in a buffer
@@ -104,30 +111,94 @@ impl model::Vertex for SpaceshipRaw {
     }
 }
 
-fn spaceship_to_raw(spaceship: &Spaceship) -> SpaceshipRaw {
-    let model = cgmath::Matrix4::from_translation(spaceship.position)
-        * cgmath::Matrix4::from(spaceship.orientation);
+fn spaceship_to_raw(transform: &Transform) -> SpaceshipRaw {
+    let model = cgmath::Matrix4::from_translation(transform.position)
+        * cgmath::Matrix4::from(transform.orientation);
     SpaceshipRaw {
         model: model.into(),
-        normal: cgmath::Matrix3::from(spaceship.orientation).into(),
+        normal: cgmath::Matrix3::from(transform.orientation).into(),
     }
 }
 
+// The kind field of the shaders' Light struct
+const LIGHT_KIND_POINT: u32 = 0;
+const LIGHT_KIND_DIRECTIONAL: u32 = 1;
+const LIGHT_KIND_SPOT: u32 = 2;
+
 #[repr(C)]
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 struct LightRaw {
     pub position: [f32; 3],
-    // Due to uniforms requring 16 byte (4 float) spacing, we need to use a padding field here
-    _padding: u32,
+    // Fills the 4 bytes after the vec3, which would be padding otherwise
+    pub kind: u32,
     pub color: [f32; 3],
+    pub range: f32,
+    // Normalized. Unused by point lights
+    pub direction: [f32; 3],
+    // Cosines, so the shader can compare them against a dot product
+    pub inner_cone_cos: f32,
+    pub outer_cone_cos: f32,
+    // Whether the light has a shadow map. Its kind says which one
+    pub casts_shadow: u32,
+    // Array elements in a uniform are 16 byte aligned
+    _padding: [u32; 2],
 }
 
-fn light_to_raw(light: &Light) -> LightRaw {
-    LightRaw {
-        position: [light.position.x, light.position.y, light.position.z],
-        _padding: 0,
+// Every light at once, as the shaders' light array and how much of it is in use
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct LightsRaw {
+    pub count: u32,
+    _padding: [u32; 3],
+    pub lights: [LightRaw; MAX_LIGHTS],
+}
+
+fn light_to_raw(light: &Light, alpha: f32) -> LightRaw {
+    let position = light.interpolated_position(alpha);
+    let mut raw = LightRaw {
+        position: position.into(),
         color: light.color,
+        ..bytemuck::Zeroable::zeroed()
+    };
+
+    match light.kind {
+        LightKind::Point { range } => {
+            raw.kind = LIGHT_KIND_POINT;
+            raw.range = range;
+        }
+        LightKind::Directional { direction } => {
+            raw.kind = LIGHT_KIND_DIRECTIONAL;
+            raw.direction = direction.normalize().into();
+        }
+        LightKind::Spot {
+            range,
+            direction,
+            inner_cone_degrees,
+            outer_cone_degrees,
+        } => {
+            raw.kind = LIGHT_KIND_SPOT;
+            raw.range = range;
+            raw.direction = direction.normalize().into();
+            raw.inner_cone_cos = cgmath::Deg(inner_cone_degrees).cos();
+            raw.outer_cone_cos = cgmath::Deg(outer_cone_degrees).cos();
+        }
     }
+
+    raw
+}
+
+fn lights_to_raw(lights: &[Light], alpha: f32) -> LightsRaw {
+    let mut raw: LightsRaw = bytemuck::Zeroable::zeroed();
+    let shadow_casters = ShadowCasters::find(lights);
+
+    raw.count = lights.len().min(MAX_LIGHTS) as u32;
+
+    for (index, (light, light_raw)) in lights.iter().zip(raw.lights.iter_mut()).enumerate() {
+        *light_raw = light_to_raw(light, alpha);
+        light_raw.casts_shadow = shadow_casters.contains(index) as u32;
+    }
+
+    raw
 }
 
 pub struct State {
@@ -139,14 +210,23 @@ pub struct State {
     sc_desc: wgpu::SwapChainDescriptor,
     swap_chain: wgpu::SwapChain,
     render_pipeline: wgpu::RenderPipeline,
+    // for a spaceship model with a skeleton
+    skinned_render_pipeline: wgpu::RenderPipeline,
     uniform_buffer: wgpu::Buffer,
+    uniform_bind_group_layout: wgpu::BindGroupLayout,
+    // the camera uniforms and the joint palette, which is recreated when it grows
     uniform_bind_group: wgpu::BindGroup,
+    palette_buffer: wgpu::Buffer,
+    palette_size: usize,
     instance_buffer: wgpu::Buffer,
     light_buffer: wgpu::Buffer,
     light_bind_group: wgpu::BindGroup,
     light_render_pipeline: wgpu::RenderPipeline,
+    shadow_maps: ShadowMaps,
     // external state
     spaceship_model: model::Model,
+    // where the models were loaded from, for snapshots
+    model_files: ModelsDescription,
     light_model: model::Model,
     depth_texture: texture::Texture,
     camera: camera::Camera,
@@ -154,14 +234,16 @@ pub struct State {
     camera_controller: camera::CameraController,
     // module state
     uniforms: Uniforms,
-    instances: HashMap<u16, Spaceship>,
-    light: Light,
+    world: World,
+    timestep: FixedTimestep,
+    // when set, input comes from a recording instead of only the devices
+    replay: Option<Replay>,
     mouse_pressed: bool,
     is_paused: bool,
 }
 
 impl State {
-    pub async fn new(window: &Window) -> Self {
+    pub async fn new(window: &Window, world: World, view: &SceneView, tick_rate: u32) -> Self {
         let size = window.inner_size();
 
         // The instance is a handle to our GPU
@@ -241,21 +323,33 @@ impl State {
                         },
                         count: None,
                     },
+                    // material
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 4,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
                 ],
                 label: Some("texture_bind_group_layout"),
             });
 
-        let instances = HashMap::new();
         let instance_buffer = device.create_buffer(&wgpu::BufferDescriptor {
             label: Some("Instance Buffer"),
             usage: wgpu::BufferUsage::VERTEX | wgpu::BufferUsage::COPY_DST,
-            // TODO: calculate this to get the real value that fits all entities
-            size: 256_000,
+            size: (MAX_SPACESHIPS * std::mem::size_of::<SpaceshipRaw>()) as wgpu::BufferAddress,
             mapped_at_creation: false,
         });
 
-        let camera =
-            camera::Camera::new((-17.0, 8.0, 20.0), cgmath::Deg(-45.0), cgmath::Deg(-20.0));
+        let camera = camera::Camera::new(
+            cgmath::Point3::from_vec(view.camera.position),
+            cgmath::Deg(view.camera.yaw_degrees),
+            cgmath::Deg(view.camera.pitch_degrees),
+        );
         let projection =
             camera::Projection::new(sc_desc.width, sc_desc.height, cgmath::Deg(45.0), 0.1, 100.0);
         let camera_controller = camera::CameraController::new(4.0, 0.4);
@@ -272,35 +366,50 @@ impl State {
 
         let uniform_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                entries: &[wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStage::VERTEX | wgpu::ShaderStage::FRAGMENT,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStage::VERTEX | wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
                     },
-                    count: None,
-                }],
+                    // joint palette
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStage::VERTEX,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Storage { read_only: true },
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                ],
                 label: Some("uniform_bind_group_layout"),
             });
 
-        let uniform_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &uniform_bind_group_layout,
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: uniform_buffer.as_entire_binding(),
-            }],
-            label: Some("uniform_bind_group"),
-        });
+        // room for a single joint until a skinned model needs more
+        let palette_size = PALETTE_HEADER_SIZE + PALETTE_MATRIX_SIZE;
+        let palette_buffer = create_palette_buffer(&device, palette_size);
+        let uniform_bind_group = create_uniform_bind_group(
+            &device,
+            &uniform_bind_group_layout,
+            &uniform_buffer,
+            &palette_buffer,
+        );
 
-        let res_dir = std::path::Path::new(env!("OUT_DIR")).join("res");
+        let res_dir = scene::res_dir();
 
         let spaceship_model = model::Model::load(
             &device,
             &queue,
             &texture_bind_group_layout,
-            res_dir.join("spaceship.obj"),
+            res_dir.join(&view.models.spaceship),
+            view.models.fallbacks,
         )
         .unwrap();
 
@@ -308,15 +417,37 @@ impl State {
             &device,
             &queue,
             &texture_bind_group_layout,
-            res_dir.join("cube.obj"),
+            res_dir.join(&view.models.light),
+            view.models.fallbacks,
         )
         .unwrap();
 
-        let light = Light::new(cgmath::Vector3::new(0.0, 0.0, 0.0), [1.0, 0.8, 0.7]);
+        for spaceship in world.spaceships() {
+            let clip = match &spaceship.animation {
+                Some(playback) => &playback.clip,
+                None => continue,
+            };
+
+            if spaceship_model.skeleton.is_none() {
+                log::warn!(
+                    "Spaceship {} plays {:?}, but {} has no skeleton to animate",
+                    spaceship.id,
+                    clip,
+                    view.models.spaceship
+                );
+            } else if spaceship_model.clip(clip).is_none() {
+                log::warn!(
+                    "Spaceship {} plays {:?}, which isn't a clip in {}, so it stays in its rest pose",
+                    spaceship.id,
+                    clip,
+                    view.models.spaceship
+                );
+            }
+        }
 
         let light_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Light VB"),
-            contents: bytemuck::cast_slice(&[light_to_raw(&light)]),
+            contents: bytemuck::cast_slice(&[lights_to_raw(world.lights(), 1.0)]),
             usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
         });
 
@@ -347,6 +478,18 @@ impl State {
         let depth_texture =
             texture::Texture::create_depth_texture(&device, &sc_desc, "depth_texture");
 
+        let shadow_maps = ShadowMaps::new(
+            &device,
+            view.shadows,
+            &[model::ModelVertex::desc(), SpaceshipRaw::desc()],
+            &[
+                model::ModelVertex::desc(),
+                SpaceshipRaw::desc(),
+                model::SkinVertex::desc(),
+            ],
+            &uniform_bind_group_layout,
+        );
+
         let render_pipeline_layout =
             device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
                 label: Some("Render Pipeline Layout"),
@@ -354,6 +497,7 @@ impl State {
                     &texture_bind_group_layout,
                     &uniform_bind_group_layout,
                     &light_bind_group_layout,
+                    &shadow_maps.bind_group_layout,
                 ],
                 push_constant_ranges: &[],
             });
@@ -371,6 +515,28 @@ impl State {
                 Some(texture::Texture::DEPTH_FORMAT),
                 &[model::ModelVertex::desc(), SpaceshipRaw::desc()],
                 shader,
+                "main",
+            )
+        };
+
+        let skinned_render_pipeline = {
+            let shader = wgpu::ShaderModuleDescriptor {
+                label: Some("Skinned Shader"),
+                flags: wgpu::ShaderFlags::all(),
+                source: wgpu::ShaderSource::Wgsl(include_str!("shader.wgsl").into()),
+            };
+            create_render_pipeline(
+                &device,
+                &render_pipeline_layout,
+                sc_desc.format,
+                Some(texture::Texture::DEPTH_FORMAT),
+                &[
+                    model::ModelVertex::desc(),
+                    SpaceshipRaw::desc(),
+                    model::SkinVertex::desc(),
+                ],
+                shader,
+                "main_skinned",
             )
         };
 
@@ -392,6 +558,7 @@ impl State {
                 Some(texture::Texture::DEPTH_FORMAT),
                 &[model::ModelVertex::desc()],
                 shader,
+                "main",
             )
         };
 
@@ -403,21 +570,28 @@ impl State {
             swap_chain,
             size,
             render_pipeline,
+            skinned_render_pipeline,
             depth_texture,
             uniform_buffer,
+            uniform_bind_group_layout,
             uniform_bind_group,
+            palette_buffer,
+            palette_size,
             instance_buffer,
             light_buffer,
             light_bind_group,
             light_render_pipeline,
+            shadow_maps,
             spaceship_model,
+            model_files: view.models.clone(),
             light_model,
             camera,
             projection,
             camera_controller,
             uniforms,
-            instances,
-            light,
+            world,
+            timestep: FixedTimestep::new(tick_rate),
+            replay: None,
             mouse_pressed: false,
             is_paused: true,
         }
@@ -474,11 +648,12 @@ impl State {
         }
     }
 
-    pub fn update(&mut self, dt: std::time::Duration) {
-        let mut rng = thread_rng();
-
+    // Called once per frame. The camera follows the frame time, while the
+    // simulation advances in fixed ticks and is drawn in between them
+    pub fn update(&mut self, frame_time: std::time::Duration) {
         // the camera
-        self.camera_controller.update_camera(&mut self.camera, dt);
+        self.camera_controller
+            .update_camera(&mut self.camera, frame_time);
         self.uniforms
             .update_view_proj(&self.camera, &self.projection);
 
@@ -488,78 +663,32 @@ impl State {
             bytemuck::cast_slice(&[self.uniforms]),
         );
 
-        if self.is_paused {
-            return;
-        }
+        self.feed_replay_input();
 
-        // the light
-        match self.light.chase_target_id {
-            // chase the current target
-            Some(id) => match self.instances.get(&id) {
-                Some(spaceship) => {
-                    let steering_output = steering::seek(&self.light, spaceship);
-                    self.light.update(steering_output, dt);
-
-                    let distance_to_spaceship =
-                        (self.light.position - spaceship.position).magnitude();
-
-                    if distance_to_spaceship < CHASE_STOP_DISTANCE {
-                        let prev_id = spaceship.id;
-                        let next_target_id = self
-                            .instances
-                            .keys()
-                            .filter(|k| **k != prev_id)
-                            .choose(&mut rng);
-
-                        self.light.chase_target_id = next_target_id.cloned();
-                    };
+        // the leftover time is kept while paused, so that the scene stays drawn
+        // where it was instead of jumping back to the last tick
+        if !self.is_paused {
+            for _ in 0..self.timestep.advance(frame_time) {
+                if self.is_paused {
+                    break;
                 }
 
-                None => (),
-            },
-
-            // no active target, choose one
-            None => self.light.chase_target_id = self.instances.keys().next().cloned(),
+                self.world.step(self.timestep.tick());
+                self.feed_replay_input();
+            }
         }
 
-        self.light.update_color();
+        let alpha = self.timestep.alpha();
 
         self.queue.write_buffer(
             &self.light_buffer,
             0,
-            bytemuck::cast_slice(&[light_to_raw(&self.light)]),
+            bytemuck::cast_slice(&[lights_to_raw(self.world.lights(), alpha)]),
         );
-
-        // the spaceships
-        println!("-> begin ship update");
-
-        for spaceship in self.instances.values_mut() {
-            println!("ship id # {:?}", spaceship.id);
-            println!("spaceship.orientation {:?}", spaceship.orientation);
-            println!("spaceship.rotation {:?}", spaceship.rotation);
-
-            let distance_to_light = (spaceship.position - self.light.position).magnitude();
-            let next_state = if distance_to_light < CHASE_STOP_DISTANCE * 3.0 {
-                SpaceshipState::Fleeing
-            } else {
-                SpaceshipState::Idle
-            };
-
-            spaceship.state = next_state;
-
-            // choose steering behavior based on the spaceship state
-            let steering_output = steering::align(
-                spaceship,
-                &DummyKinematic::from_orientation(cgmath::Quaternion::from_angle_y(cgmath::Rad(
-                    180.0,
-                ))),
-            );
-
-            spaceship.update(steering_output, dt);
-            println!("\n");
-        }
-
-        self.update_spaceship_buffer();
+        self.shadow_maps
+            .update(&self.queue, self.world.lights(), alpha);
+        self.update_spaceship_buffer(alpha);
+        self.update_palette_buffer(alpha);
     }
 
     pub fn render(&mut self) -> Result<(), wgpu::SwapChainError> {
@@ -571,6 +700,15 @@ impl State {
                 label: Some("Render Encoder"),
             });
 
+        // the ships shade each other, so their depth from the lights comes first
+        self.shadow_maps.render(
+            &mut encoder,
+            &self.spaceship_model,
+            &self.instance_buffer,
+            0..self.world.spaceships().count() as u32,
+            &self.uniform_bind_group,
+        );
+
         let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
             label: Some("Render Pass"),
             color_attachments: &[wgpu::RenderPassColorAttachment {
@@ -594,16 +732,23 @@ impl State {
         render_pass.set_vertex_buffer(1, self.instance_buffer.slice(..));
 
         render_pass.set_pipeline(&self.light_render_pipeline);
-        render_pass.draw_light_model(
+        render_pass.draw_light_model_instanced(
             &self.light_model,
+            0..self.world.lights().len().min(MAX_LIGHTS) as u32,
             &self.uniform_bind_group,
             &self.light_bind_group,
         );
 
-        render_pass.set_pipeline(&self.render_pipeline);
+        if self.spaceship_model.skeleton.is_some() {
+            render_pass.set_pipeline(&self.skinned_render_pipeline);
+        } else {
+            render_pass.set_pipeline(&self.render_pipeline);
+        }
+
+        render_pass.set_bind_group(3, &self.shadow_maps.bind_group, &[]);
         render_pass.draw_model_instanced(
             &self.spaceship_model,
-            0..self.instances.len() as u32,
+            0..self.world.spaceships().count() as u32,
             &self.uniform_bind_group,
             &self.light_bind_group,
         );
@@ -616,23 +761,67 @@ impl State {
         Ok(())
     }
 
-    pub fn add_spaceship(
-        &mut self,
-        id: u16,
-        position: cgmath::Vector3<f32>,
-        orientation: cgmath::Quaternion<f32>,
-    ) {
-        let spaceship = Spaceship::new(id, position, orientation);
+    pub fn world(&self) -> &World {
+        &self.world
+    }
+
+    // The world as it is now, seen from the current camera
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            view: SceneView {
+                camera: CameraDescription {
+                    position: self.camera.position.to_vec(),
+                    yaw_degrees: cgmath::Deg::from(self.camera.yaw()).0,
+                    pitch_degrees: cgmath::Deg::from(self.camera.pitch()).0,
+                },
+                models: self.model_files.clone(),
+                shadows: self.shadow_maps.settings(),
+            },
+            world: self.world.clone(),
+        }
+    }
+
+    pub fn start_replay(&mut self, replay: Replay) {
+        self.replay = Some(replay);
+    }
+
+    // Feed in the recorded input due on the current tick, and stop once the recording ends
+    fn feed_replay_input(&mut self) {
+        let tick = self.world.tick();
+        let due = match &mut self.replay {
+            Some(replay) => replay.take_due(tick),
+            None => return,
+        };
+
+        for event in due.iter() {
+            self.input(event);
+        }
+
+        let replay = match &self.replay {
+            Some(replay) if tick >= replay.final_tick => replay,
+            _ => return,
+        };
 
-        self.instances.insert(id, spaceship);
-        self.update_spaceship_buffer();
+        if self.world.state_hash() == replay.final_hash {
+            println!("Replay reached tick {} and matches the recording", tick);
+        } else {
+            println!(
+                "Replay reached tick {} but its state hash {:016x} differs from the recorded {:016x}",
+                tick,
+                self.world.state_hash(),
+                replay.final_hash
+            );
+        }
+
+        self.is_paused = true;
+        self.replay = None;
     }
 
-    fn update_spaceship_buffer(&mut self) {
+    fn update_spaceship_buffer(&mut self, alpha: f32) {
         let instance_data = self
-            .instances
-            .values()
-            .map(spaceship_to_raw)
+            .world
+            .spaceships()
+            .map(|spaceship| spaceship_to_raw(&spaceship.interpolated_transform(alpha)))
             .collect::<Vec<_>>();
 
         self.queue.write_buffer(
@@ -641,6 +830,87 @@ impl State {
             bytemuck::cast_slice(&instance_data),
         );
     }
+
+    // Pose every spaceship's skeleton where its clip is between the last two ticks, in
+    // the same order as the instances
+    fn update_palette_buffer(&mut self, alpha: f32) {
+        let skeleton = match &self.spaceship_model.skeleton {
+            Some(skeleton) => skeleton,
+            None => return,
+        };
+        let joints_per_instance = skeleton.joint_count() as u32;
+        let mut contents = bytemuck::cast_slice(&[joints_per_instance, 0, 0, 0]).to_vec();
+
+        for spaceship in self.world.spaceships() {
+            let playing = spaceship
+                .animation
+                .as_ref()
+                .and_then(|playback| Some((playback, self.spaceship_model.clip(&playback.clip)?)));
+            let palette = match playing {
+                Some((playback, clip)) => {
+                    let time = clip_time(
+                        playback.interpolated_time(alpha),
+                        clip.duration,
+                        playback.looping,
+                    );
+
+                    skeleton.animate(Some(clip), time)
+                }
+                // unknown clips were warned about when the model was loaded
+                None => skeleton.animate(None, 0.0),
+            };
+            let raw = palette
+                .into_iter()
+                .map(|matrix| matrix.into())
+                .collect::<Vec<[[f32; 4]; 4]>>();
+
+            contents.extend_from_slice(bytemuck::cast_slice(&raw));
+        }
+
+        if contents.len() > self.palette_size {
+            self.palette_size = contents.len().next_power_of_two();
+            self.palette_buffer = create_palette_buffer(&self.device, self.palette_size);
+            self.uniform_bind_group = create_uniform_bind_group(
+                &self.device,
+                &self.uniform_bind_group_layout,
+                &self.uniform_buffer,
+                &self.palette_buffer,
+            );
+        }
+
+        self.queue.write_buffer(&self.palette_buffer, 0, &contents);
+    }
+}
+
+fn create_palette_buffer(device: &wgpu::Device, size: usize) -> wgpu::Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Joint Palette Buffer"),
+        usage: wgpu::BufferUsage::STORAGE | wgpu::BufferUsage::COPY_DST,
+        size: size as wgpu::BufferAddress,
+        mapped_at_creation: false,
+    })
+}
+
+fn create_uniform_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    uniform_buffer: &wgpu::Buffer,
+    palette_buffer: &wgpu::Buffer,
+) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: uniform_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: palette_buffer.as_entire_binding(),
+            },
+        ],
+        label: Some("uniform_bind_group"),
+    })
 }
 
 fn create_render_pipeline(
@@ -650,6 +920,7 @@ fn create_render_pipeline(
     depth_format: Option<wgpu::TextureFormat>,
     vertex_layouts: &[wgpu::VertexBufferLayout],
     shader: wgpu::ShaderModuleDescriptor,
+    vertex_entry_point: &str,
 ) -> wgpu::RenderPipeline {
     let shader = device.create_shader_module(&shader);
 
@@ -658,7 +929,7 @@ fn create_render_pipeline(
         layout: Some(&layout),
         vertex: wgpu::VertexState {
             module: &shader,
-            entry_point: "main",
+            entry_point: vertex_entry_point,
             buffers: vertex_layouts,
         },
         fragment: Some(wgpu::FragmentState {
@@ -699,3 +970,160 @@ fn create_render_pipeline(
         },
     })
 }
+
+//
+// Tests
+//
+
+#[cfg(test)]
+mod tests {
+    use crate::model::MaterialUniform;
+    use crate::shadow::ShadowsRaw;
+    use crate::state::{LightRaw, LightsRaw, PALETTE_HEADER_SIZE};
+    use crate::world::MAX_LIGHTS;
+    use std::mem::{offset_of, size_of};
+
+    fn validate(source: &str) -> naga::Module {
+        let module = naga::front::wgsl::parse_str(source).unwrap();
+
+        naga::valid::Validator::new(
+            naga::valid::ValidationFlags::all(),
+            naga::valid::Capabilities::empty(),
+        )
+        .validate(&module)
+        .unwrap();
+
+        module
+    }
+
+    // Where the shader puts each member of a struct, and the struct's size
+    fn struct_layout(module: &naga::Module, name: &str) -> (Vec<(String, u32)>, u32) {
+        let struct_type = module
+            .types
+            .iter()
+            .find(|(_, ty)| ty.name.as_deref() == Some(name))
+            .unwrap()
+            .1;
+
+        match &struct_type.inner {
+            naga::TypeInner::Struct { members, span, .. } => (
+                members
+                    .iter()
+                    .map(|member| (member.name.clone().unwrap(), member.offset))
+                    .collect(),
+                *span,
+            ),
+            _ => panic!("{} is not a struct", name),
+        }
+    }
+
+    #[test]
+    fn test_shaders_match_uniform_layouts() {
+        let expected_light = (
+            vec![
+                (
+                    "position".to_string(),
+                    offset_of!(LightRaw, position) as u32,
+                ),
+                ("kind".to_string(), offset_of!(LightRaw, kind) as u32),
+                ("color".to_string(), offset_of!(LightRaw, color) as u32),
+                ("range".to_string(), offset_of!(LightRaw, range) as u32),
+                (
+                    "direction".to_string(),
+                    offset_of!(LightRaw, direction) as u32,
+                ),
+                (
+                    "inner_cone_cos".to_string(),
+                    offset_of!(LightRaw, inner_cone_cos) as u32,
+                ),
+                (
+                    "outer_cone_cos".to_string(),
+                    offset_of!(LightRaw, outer_cone_cos) as u32,
+                ),
+                (
+                    "casts_shadow".to_string(),
+                    offset_of!(LightRaw, casts_shadow) as u32,
+                ),
+            ],
+            size_of::<LightRaw>() as u32,
+        );
+        let expected_lights = (
+            vec![
+                ("count".to_string(), offset_of!(LightsRaw, count) as u32),
+                ("lights".to_string(), offset_of!(LightsRaw, lights) as u32),
+            ],
+            size_of::<LightsRaw>() as u32,
+        );
+
+        for source in [include_str!("shader.wgsl"), include_str!("light.wgsl")] {
+            let module = validate(source);
+
+            assert_eq!(struct_layout(&module, "Light"), expected_light);
+            assert_eq!(struct_layout(&module, "Lights"), expected_lights);
+        }
+
+        let expected_shadows = (
+            vec![
+                (
+                    "directional_view_proj".to_string(),
+                    offset_of!(ShadowsRaw, directional_view_proj) as u32,
+                ),
+                (
+                    "point_near".to_string(),
+                    offset_of!(ShadowsRaw, point_near) as u32,
+                ),
+                (
+                    "point_far".to_string(),
+                    offset_of!(ShadowsRaw, point_far) as u32,
+                ),
+                (
+                    "map_size".to_string(),
+                    offset_of!(ShadowsRaw, map_size) as u32,
+                ),
+            ],
+            size_of::<ShadowsRaw>() as u32,
+        );
+
+        let expected_material = (
+            vec![
+                (
+                    "base_color".to_string(),
+                    offset_of!(MaterialUniform, base_color) as u32,
+                ),
+                (
+                    "specular".to_string(),
+                    offset_of!(MaterialUniform, specular) as u32,
+                ),
+                (
+                    "shininess".to_string(),
+                    offset_of!(MaterialUniform, shininess) as u32,
+                ),
+                (
+                    "emissive".to_string(),
+                    offset_of!(MaterialUniform, emissive) as u32,
+                ),
+            ],
+            size_of::<MaterialUniform>() as u32,
+        );
+        let module = validate(include_str!("shader.wgsl"));
+
+        assert_eq!(struct_layout(&module, "Shadows"), expected_shadows);
+        assert_eq!(struct_layout(&module, "Material"), expected_material);
+
+        for source in [include_str!("shader.wgsl"), include_str!("shadow.wgsl")] {
+            let module = validate(source);
+
+            assert_eq!(
+                struct_layout(&module, "JointPalette").0,
+                vec![
+                    ("joints_per_instance".to_string(), 0),
+                    ("matrices".to_string(), PALETTE_HEADER_SIZE as u32),
+                ]
+            );
+        }
+
+        // The shaders declare a stride of 64 and a fixed number of lights
+        assert_eq!(size_of::<LightRaw>(), 64);
+        assert_eq!(size_of::<LightsRaw>(), 16 + 64 * MAX_LIGHTS);
+    }
+}
@@ -1,14 +1,22 @@
+use crate::obstacle::Obstacle;
+use crate::path::Path;
+
 use cgmath::prelude::*;
 use cgmath::{InnerSpace, Quaternion, Rad, Vector3, Zero};
 use rand::Rng;
+use serde::{Deserialize, Serialize};
 use std::time::Duration;
 
+#[derive(Debug, Clone, Copy)]
 pub struct KinematicProps {
     pub position: Vector3<f32>,
     pub orientation: Quaternion<f32>,
     pub velocity: Vector3<f32>,
     pub rotation: Vector3<f32>,
     pub max_acceleration: f32,
+    pub max_speed: f32,
+    pub max_angular_acceleration: f32,
+    pub max_rotation: f32,
 }
 
 pub trait Kinematic {
@@ -16,7 +24,7 @@ pub trait Kinematic {
     fn update(&mut self, steering: SteeringOutput, delta: Duration);
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct SteeringOutput {
     pub linear: Option<Vector3<f32>>,
     pub angular: Option<Vector3<f32>>,
@@ -29,6 +37,14 @@ impl SteeringOutput {
             angular: None,
         }
     }
+
+    // Tiny outputs are treated as "no steering" when arbitrating between behaviors
+    pub fn is_above(&self, epsilon: f32) -> bool {
+        let linear = self.linear.map_or(0.0, |linear| linear.magnitude());
+        let angular = self.angular.map_or(0.0, |angular| angular.magnitude());
+
+        linear > epsilon || angular > epsilon
+    }
 }
 
 pub struct DummyKinematic {
@@ -37,6 +53,9 @@ pub struct DummyKinematic {
     pub velocity: Vector3<f32>,
     pub rotation: Vector3<f32>,
     pub max_acceleration: f32,
+    pub max_speed: f32,
+    pub max_angular_acceleration: f32,
+    pub max_rotation: f32,
 }
 
 impl DummyKinematic {
@@ -47,6 +66,9 @@ impl DummyKinematic {
             velocity: Vector3::zero(),
             rotation: Vector3::zero(),
             max_acceleration: 0.0,
+            max_speed: 0.0,
+            max_angular_acceleration: 0.0,
+            max_rotation: 0.0,
         }
     }
 
@@ -57,6 +79,9 @@ impl DummyKinematic {
             velocity: Vector3::zero(),
             rotation: Vector3::zero(),
             max_acceleration: 0.0,
+            max_speed: 0.0,
+            max_angular_acceleration: 0.0,
+            max_rotation: 0.0,
         }
     }
 }
@@ -69,6 +94,9 @@ impl Kinematic for DummyKinematic {
             velocity: self.velocity,
             rotation: self.rotation,
             max_acceleration: self.max_acceleration,
+            max_speed: self.max_speed,
+            max_angular_acceleration: self.max_angular_acceleration,
+            max_rotation: self.max_rotation,
         }
     }
 
@@ -77,23 +105,61 @@ impl Kinematic for DummyKinematic {
 
 // Mix steering behaviors
 
-pub fn combine(steering_behaviors: Vec<&SteeringOutput>) -> SteeringOutput {
+// Sum the behaviors scaled by their weights, capped to what the character can achieve
+pub fn blend_weighted(
+    character_source: &impl Kinematic,
+    weighted_behaviors: &[(SteeringOutput, f32)],
+) -> SteeringOutput {
+    let character = character_source.props();
     let mut result = SteeringOutput::new();
 
-    for behavior in steering_behaviors {
-        result.linear = match (result.linear, behavior.linear) {
-            (Some(a), Some(b)) => (Some(a + b)),
-            _ => None,
-        };
-        result.angular = match (result.angular, behavior.angular) {
-            (Some(a), Some(b)) => (Some(a + b)),
-            _ => None,
-        };
+    for (behavior, weight) in weighted_behaviors {
+        result.linear = add_weighted(result.linear, behavior.linear, *weight);
+        result.angular = add_weighted(result.angular, behavior.angular, *weight);
     }
 
+    result.linear = result
+        .linear
+        .map(|linear| clamp_magnitude(linear, character.max_acceleration));
+    result.angular = result
+        .angular
+        .map(|angular| clamp_magnitude(angular, character.max_angular_acceleration));
+
     result
 }
 
+// Groups are ordered by priority. The first group with a significant output wins,
+// otherwise the lowest priority group is used as is
+pub fn blend_priority(
+    character_source: &impl Kinematic,
+    groups: &[Vec<(SteeringOutput, f32)>],
+    epsilon: f32,
+) -> SteeringOutput {
+    let mut result = SteeringOutput::new();
+
+    for group in groups {
+        result = blend_weighted(character_source, group);
+
+        if result.is_above(epsilon) {
+            return result;
+        }
+    }
+
+    result
+}
+
+fn add_weighted(
+    sum: Option<Vector3<f32>>,
+    value: Option<Vector3<f32>>,
+    weight: f32,
+) -> Option<Vector3<f32>> {
+    match (sum, value) {
+        (Some(sum), Some(value)) => Some(sum + value * weight),
+        (None, Some(value)) => Some(value * weight),
+        (sum, None) => sum,
+    }
+}
+
 // Steering behaviors - linear
 
 pub fn stop(character_source: &impl Kinematic) -> SteeringOutput {
@@ -122,6 +188,43 @@ pub fn seek(character_source: &impl Kinematic, target_source: &impl Kinematic) -
     }
 }
 
+pub fn arrive(
+    character_source: &impl Kinematic,
+    target_source: &impl Kinematic,
+    target_radius: f32,
+    slow_radius: f32,
+) -> SteeringOutput {
+    let character = character_source.props();
+    let target = target_source.props();
+    // The time over which to achieve target speed
+    let time_to_target: f32 = 0.1;
+
+    let direction = target.position - character.position;
+    let distance = direction.magnitude();
+
+    // Within the target radius the target speed is zero, which brakes the character
+    let target_speed = if distance < target_radius {
+        0.0
+    } else if distance > slow_radius {
+        character.max_speed
+    } else {
+        character.max_speed * distance / slow_radius
+    };
+
+    let target_velocity = if distance > 0.0 {
+        direction.normalize() * target_speed
+    } else {
+        Vector3::zero()
+    };
+
+    let acceleration = (target_velocity - character.velocity) / time_to_target;
+
+    SteeringOutput {
+        linear: Some(clamp_magnitude(acceleration, character.max_acceleration)),
+        angular: None,
+    }
+}
+
 pub fn flee(character_source: &impl Kinematic, target_source: &impl Kinematic) -> SteeringOutput {
     let character = character_source.props();
     let target = target_source.props();
@@ -138,77 +241,348 @@ pub fn flee(character_source: &impl Kinematic, target_source: &impl Kinematic) -
     }
 }
 
-// Steering behaviors - with angular component
+pub fn pursue(
+    character_source: &impl Kinematic,
+    target_source: &impl Kinematic,
+    max_prediction: f32,
+) -> SteeringOutput {
+    let predicted_position = predict_position(character_source, target_source, max_prediction);
 
-// A minimal version of the align steering behavior for 2D rotation
-fn align_2d(
-    current_rotation: f32,
-    current_orientation: Rad<f32>,
-    target_orientation: Rad<f32>,
-) -> Option<f32> {
-    // Radians per second squared
-    let max_angular_acceleration: f32 = 10.0;
-    // Radians per second
-    let max_rotation: f32 = 1.0;
-    let target_radius: Rad<f32> = Rad(0.017);
-    let slow_radius: Rad<f32> = Rad(1.0);
-    // The time over which to achieve target speed
-    let time_to_target: f32 = 0.1;
+    seek(
+        character_source,
+        &DummyKinematic::from_position(predicted_position),
+    )
+}
 
-    let rotation = (target_orientation - current_orientation).normalize_signed();
-    let rotation_size = Rad(rotation.0.abs());
+pub fn evade(
+    character_source: &impl Kinematic,
+    target_source: &impl Kinematic,
+    max_prediction: f32,
+) -> SteeringOutput {
+    let predicted_position = predict_position(character_source, target_source, max_prediction);
 
-    if rotation_size < target_radius {
-        // No steering required
-        println!("angle_2d: no rotation");
-        return None;
-    }
+    flee(
+        character_source,
+        &DummyKinematic::from_position(predicted_position),
+    )
+}
 
-    println!("align_2d: rotation {:?}", rotation);
-    println!("align_2d: rotation_size {:?}", rotation_size);
+// Where the target will be once the character reaches it, assuming the target keeps its velocity
+fn predict_position(
+    character_source: &impl Kinematic,
+    target_source: &impl Kinematic,
+    max_prediction: f32,
+) -> Vector3<f32> {
+    let character = character_source.props();
+    let target = target_source.props();
 
-    let target_rotation: f32 = if rotation_size > slow_radius {
-        max_rotation
+    let distance = (target.position - character.position).magnitude();
+    let speed = character.velocity.magnitude();
+
+    // A slow character would take too long to reach the target, cap the prediction time
+    let prediction = if speed <= distance / max_prediction {
+        max_prediction
     } else {
-        max_rotation * rotation_size.0 / slow_radius.0
+        distance / speed
     };
-    println!("align_2d: target_rotation {:?}", target_rotation);
 
-    let target_rotation_with_direction: f32 = target_rotation * rotation.0 / rotation_size.0;
-    println!(
-        "align_2d: target_rotation_with_direction {:?}",
-        target_rotation_with_direction
+    target.position + target.velocity * prediction
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct WanderProps {
+    // Distance of the wander sphere center ahead of the character
+    pub offset: f32,
+    pub radius: f32,
+    // How far the wander target can be displaced per second
+    pub jitter_rate: f32,
+    // Persistent target on the wander sphere, relative to its center
+    pub target: Vector3<f32>,
+}
+
+impl WanderProps {
+    pub fn new(offset: f32, radius: f32, jitter_rate: f32) -> Self {
+        WanderProps {
+            offset,
+            radius,
+            jitter_rate,
+            target: Vector3::unit_z() * radius,
+        }
+    }
+}
+
+pub fn wander(
+    character_source: &impl Kinematic,
+    wander_props: &mut WanderProps,
+    delta: Duration,
+    rng: &mut impl Rng,
+) -> SteeringOutput {
+    let character = character_source.props();
+    let jitter = wander_props.jitter_rate * delta.as_secs_f32();
+
+    // Displace the target a little, then project it back onto the sphere
+    let displacement = Vector3::new(
+        random_binomial(rng),
+        random_binomial(rng),
+        random_binomial(rng),
     );
-    let angular_acceleration = (target_rotation_with_direction - current_rotation) / time_to_target;
-    println!("align_2d: angular_acceleration {:?}", angular_acceleration);
-    let result = angular_acceleration.clamp(-max_angular_acceleration, max_angular_acceleration);
+    let displaced_target = wander_props.target + displacement * jitter;
 
-    println!("align_2d: result {:?}", result);
+    if displaced_target.magnitude() > 0.0 {
+        wander_props.target = displaced_target.normalize() * wander_props.radius;
+    }
+
+    // Prefer the direction of travel, fall back to the orientation when still
+    let heading = if character.velocity.magnitude() > 0.0 {
+        character.velocity.normalize()
+    } else {
+        character.orientation * Vector3::unit_z()
+    };
 
-    Some(result)
+    let sphere_center = character.position + heading * wander_props.offset;
+
+    seek(
+        character_source,
+        &DummyKinematic::from_position(sphere_center + wander_props.target),
+    )
 }
 
+// Seek a point a little further along the path, coming to a stop at the end of an open path
+pub fn follow_path(
+    character_source: &impl Kinematic,
+    path: &Path,
+    path_offset: f32,
+) -> SteeringOutput {
+    let character = character_source.props();
+    let current_param = path.closest_param(character.position);
+    let target_param = current_param + path_offset;
+    let target = DummyKinematic::from_position(path.position_at(target_param));
+
+    if path.is_past_end(target_param) {
+        arrive(character_source, &target, path_offset * 0.1, path_offset)
+    } else {
+        seek(character_source, &target)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AvoidanceProps {
+    // Length of the central ray cast along the velocity
+    pub lookahead: f32,
+    // Length of the shorter side rays
+    pub whisker_lookahead: f32,
+    pub whisker_angle: Rad<f32>,
+    // How far from the hit surface to aim
+    pub avoid_distance: f32,
+}
+
+pub fn avoid_obstacles(
+    character_source: &impl Kinematic,
+    obstacles: &[Obstacle],
+    avoidance_props: &AvoidanceProps,
+) -> SteeringOutput {
+    let character = character_source.props();
+
+    if character.velocity.magnitude() == 0.0 {
+        return SteeringOutput::new();
+    }
+
+    let heading = character.velocity.normalize();
+    // Any vector perpendicular to the heading works as a base for the whiskers
+    let side = if heading.cross(Vector3::unit_y()).magnitude() > 0.001 {
+        heading.cross(Vector3::unit_y()).normalize()
+    } else {
+        heading.cross(Vector3::unit_x()).normalize()
+    };
+    let up = side.cross(heading);
+
+    let mut rays = vec![(heading, avoidance_props.lookahead)];
+
+    for whisker_axis in [up, -up, side, -side] {
+        let whisker =
+            Quaternion::from_axis_angle(whisker_axis, avoidance_props.whisker_angle) * heading;
+
+        rays.push((whisker, avoidance_props.whisker_lookahead));
+    }
+
+    let closest_collision = rays
+        .iter()
+        .flat_map(|(direction, length)| {
+            obstacles.iter().filter_map(move |obstacle| {
+                obstacle.ray_cast(character.position, *direction, *length)
+            })
+        })
+        .min_by(|a, b| {
+            a.distance
+                .partial_cmp(&b.distance)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+
+    match closest_collision {
+        Some(collision) => {
+            let target = collision.position + collision.normal * avoidance_props.avoid_distance;
+
+            if target == character.position {
+                return SteeringOutput::new();
+            }
+
+            seek(character_source, &DummyKinematic::from_position(target))
+        }
+        None => SteeringOutput::new(),
+    }
+}
+
+// Steering behaviors - group
+
+#[derive(Debug, Clone, Copy)]
+pub struct FlockProps {
+    // Neighbors closer than this push the character away
+    pub separation_threshold: f32,
+    // Strength of the inverse square repulsion
+    pub decay_coefficient: f32,
+    pub separation_weight: f32,
+    pub cohesion_weight: f32,
+    pub velocity_match_weight: f32,
+}
+
+pub fn separation(
+    character_source: &impl Kinematic,
+    neighbors: &[KinematicProps],
+    threshold: f32,
+    decay_coefficient: f32,
+) -> SteeringOutput {
+    let character = character_source.props();
+    let mut result = Vector3::zero();
+
+    for neighbor in neighbors {
+        let direction = character.position - neighbor.position;
+        let distance = direction.magnitude();
+
+        if distance > 0.0 && distance < threshold {
+            // Inverse square law, capped to what the character can achieve
+            let strength =
+                (decay_coefficient / (distance * distance)).min(character.max_acceleration);
+
+            result += direction.normalize() * strength;
+        }
+    }
+
+    if result == Vector3::zero() {
+        return SteeringOutput::new();
+    }
+
+    SteeringOutput {
+        linear: Some(clamp_magnitude(result, character.max_acceleration)),
+        angular: None,
+    }
+}
+
+pub fn cohesion(character_source: &impl Kinematic, neighbors: &[KinematicProps]) -> SteeringOutput {
+    let character = character_source.props();
+
+    match center_of_mass(neighbors) {
+        Some(center) if center != character.position => {
+            seek(character_source, &DummyKinematic::from_position(center))
+        }
+        _ => SteeringOutput::new(),
+    }
+}
+
+pub fn velocity_match(
+    character_source: &impl Kinematic,
+    neighbors: &[KinematicProps],
+) -> SteeringOutput {
+    let character = character_source.props();
+    // The time over which to achieve target speed
+    let time_to_target: f32 = 0.1;
+
+    if neighbors.is_empty() {
+        return SteeringOutput::new();
+    }
+
+    let average_velocity = neighbors
+        .iter()
+        .fold(Vector3::zero(), |sum, neighbor| sum + neighbor.velocity)
+        / neighbors.len() as f32;
+    let acceleration = (average_velocity - character.velocity) / time_to_target;
+
+    SteeringOutput {
+        linear: Some(clamp_magnitude(acceleration, character.max_acceleration)),
+        angular: None,
+    }
+}
+
+pub fn flock(
+    character_source: &impl Kinematic,
+    neighbors: &[KinematicProps],
+    flock_props: &FlockProps,
+) -> SteeringOutput {
+    let weighted_behaviors = [
+        (
+            separation(
+                character_source,
+                neighbors,
+                flock_props.separation_threshold,
+                flock_props.decay_coefficient,
+            ),
+            flock_props.separation_weight,
+        ),
+        (
+            cohesion(character_source, neighbors),
+            flock_props.cohesion_weight,
+        ),
+        (
+            velocity_match(character_source, neighbors),
+            flock_props.velocity_match_weight,
+        ),
+    ];
+
+    blend_weighted(character_source, &weighted_behaviors)
+}
+
+// Steering behaviors - with angular component
+
 pub fn align(character_source: &impl Kinematic, target_source: &impl Kinematic) -> SteeringOutput {
     let character = character_source.props();
     let target = target_source.props();
+    let target_radius: Rad<f32> = Rad(0.017);
+    let slow_radius: Rad<f32> = Rad(1.0);
+    // The time over which to achieve target speed
+    let time_to_target: f32 = 0.1;
 
-    let target_orientation = (character.orientation.conjugate() * target.orientation).normalize();
-    println!("align: target_orientation {:?}", target_orientation);
-    let rotation_angle = Rad(quaternion_angle(target_orientation));
-    println!("align: rotation_angle {:?}", rotation_angle);
-    let rotation_axis = quaternion_axis(target_orientation);
-    println!("align: rotation_axis {:?}", rotation_axis);
+    // The rotation still needed, in the character's own frame like its rotation vector
+    let mut rotation_error = (character.orientation.conjugate() * target.orientation).normalize();
 
-    let current_rotation = character.rotation.magnitude();
-    println!("align: current_rotation {:?}", current_rotation);
-    let align_result = align_2d(current_rotation, Rad(0.0), rotation_angle);
+    // q and -q describe the same orientation, take the shorter way around
+    if rotation_error.s < 0.0 {
+        rotation_error = -rotation_error;
+    }
 
-    let angular_output =
-        align_result.map(|angular_acceleration| rotation_axis * angular_acceleration);
+    let rotation_angle = Rad(quaternion_angle(rotation_error));
+
+    if rotation_angle < target_radius && character.rotation.magnitude() < target_radius.0 {
+        // No steering required
+        return SteeringOutput::new();
+    }
+
+    // Rotate at full speed until within the slow radius, then ease in. Within the
+    // target radius the target rotation is zero, which stops any leftover spin
+    let target_rotation = if rotation_angle < target_radius {
+        Vector3::zero()
+    } else if rotation_angle > slow_radius {
+        quaternion_axis(rotation_error) * character.max_rotation
+    } else {
+        quaternion_axis(rotation_error) * character.max_rotation * rotation_angle.0 / slow_radius.0
+    };
+
+    let angular_acceleration = (target_rotation - character.rotation) / time_to_target;
 
     SteeringOutput {
         linear: None,
-        angular: angular_output,
+        angular: Some(clamp_magnitude(
+            angular_acceleration,
+            character.max_angular_acceleration,
+        )),
     }
 }
 
@@ -219,11 +593,7 @@ pub fn face(character_source: &impl Kinematic, target_source: &impl Kinematic) -
     let target = target_source.props();
     let direction = target.position - character.position;
 
-    println!("face: target.position {:?}", target.position);
-    println!("face: direction {:?}", direction);
-
     if direction.magnitude() == 0.0 {
-        println!("face: no steering");
         return SteeringOutput::new();
     }
 
@@ -235,22 +605,33 @@ pub fn face(character_source: &impl Kinematic, target_source: &impl Kinematic) -
     )
 }
 
+pub fn look_where_you_are_going(character_source: &impl Kinematic) -> SteeringOutput {
+    let character = character_source.props();
+
+    if character.velocity.magnitude() == 0.0 {
+        return SteeringOutput::new();
+    }
+
+    let target_orientation = face_direction(character.velocity);
+
+    align(
+        character_source,
+        &DummyKinematic::from_orientation(target_orientation),
+    )
+}
+
 fn face_direction(direction: Vector3<f32>) -> Quaternion<f32> {
     let base_z_vector = BASE_ORIENTATION * Vector3::unit_z();
     let direction = direction.normalize();
 
-    println!("face_direction: direction {:?}", direction);
-
     if base_z_vector == direction {
-        println!("face_direction: base_orientation");
         BASE_ORIENTATION
     } else if base_z_vector == -direction {
-        println!("face_direction: base_orientation inverse");
-        BASE_ORIENTATION.conjugate()
+        // Any axis perpendicular to the base direction works for a half turn
+        BASE_ORIENTATION * Quaternion::from_angle_y(Rad(std::f32::consts::PI))
     } else {
         // Find the minimum rotation to the target
         let axis = base_z_vector.cross(direction);
-        println!("face_direction: axis {:?}", axis);
 
         // Numerical accuracy can sometimes cause a zero axis
         // default to base orientation to avoid a NaN Quaternion
@@ -260,7 +641,6 @@ fn face_direction(direction: Vector3<f32>) -> Quaternion<f32> {
 
         let dot = base_z_vector.dot(direction);
         let angle = axis.magnitude().atan2(dot);
-        println!("face_direction: angle {:?}", angle);
 
         Quaternion::from_axis_angle(axis.normalize(), Rad(angle))
     }
@@ -268,16 +648,36 @@ fn face_direction(direction: Vector3<f32>) -> Quaternion<f32> {
 
 // Utility
 
-fn random_binomial() -> f32 {
-    let mut rng = rand::thread_rng();
+fn random_binomial(rng: &mut impl Rng) -> f32 {
     let a = rng.gen_range(0.0..1.0);
     let b = rng.gen_range(0.0..1.0);
 
     a - b
 }
 
+fn clamp_magnitude(vector: Vector3<f32>, max_magnitude: f32) -> Vector3<f32> {
+    if vector.magnitude() > max_magnitude {
+        vector.normalize() * max_magnitude
+    } else {
+        vector
+    }
+}
+
+fn center_of_mass(kinematics: &[KinematicProps]) -> Option<Vector3<f32>> {
+    if kinematics.is_empty() {
+        return None;
+    }
+
+    let sum = kinematics
+        .iter()
+        .fold(Vector3::zero(), |sum, kinematic| sum + kinematic.position);
+
+    Some(sum / kinematics.len() as f32)
+}
+
 fn quaternion_angle(quaternion: Quaternion<f32>) -> f32 {
-    2.0 * quaternion.s.acos()
+    // Rounding can push a unit quaternion's scalar part just past 1.0
+    2.0 * quaternion.s.clamp(-1.0, 1.0).acos()
 }
 
 fn quaternion_axis(quaternion: Quaternion<f32>) -> Vector3<f32> {
@@ -302,8 +702,19 @@ fn quaternion_axis(quaternion: Quaternion<f32>) -> Vector3<f32> {
 
 #[cfg(test)]
 mod tests {
-    use crate::steering::{quaternion_angle, quaternion_axis};
+    use crate::entity::Spaceship;
+    use crate::obstacle::Obstacle;
+    use crate::path::{Path, PathKind};
+    use crate::steering::{
+        align, arrive, avoid_obstacles, blend_priority, blend_weighted, cohesion, evade,
+        face_direction, flock, follow_path, look_where_you_are_going, pursue, quaternion_angle,
+        quaternion_axis, seek, separation, velocity_match, wander, AvoidanceProps, DummyKinematic,
+        FlockProps, Kinematic, SteeringOutput, WanderProps,
+    };
     use cgmath::{prelude::*, Quaternion, Rad, Vector3};
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+    use std::time::Duration;
 
     #[test]
     fn test_quaternion_axis_angle_extraction() {
@@ -320,4 +731,278 @@ mod tests {
         assert_eq!(quaternion_angle(q_small_angle), 0.0);
         assert_eq!(quaternion_axis(q_small_angle), base_z_axis);
     }
+
+    #[test]
+    fn test_wander_target_stays_on_sphere() {
+        let character = DummyKinematic::from_position(Vector3::new(1.0, 2.0, 3.0));
+        let mut wander_props = WanderProps::new(2.0, 1.5, 10.0);
+        let mut rng = ChaCha8Rng::seed_from_u64(1);
+
+        for _ in 0..100 {
+            let steering = wander(
+                &character,
+                &mut wander_props,
+                Duration::from_millis(16),
+                &mut rng,
+            );
+
+            assert!(steering.linear.is_some());
+            assert!((wander_props.target.magnitude() - 1.5).abs() < 0.0001);
+        }
+    }
+
+    #[test]
+    fn test_wander_is_reproducible_with_a_seed() {
+        let mut character = DummyKinematic::from_position(Vector3::new(0.0, 0.0, 0.0));
+        character.max_acceleration = 1.0;
+        let run = |seed: u64| {
+            let mut wander_props = WanderProps::new(2.0, 1.0, 4.0);
+            let mut rng = ChaCha8Rng::seed_from_u64(seed);
+
+            (0..50)
+                .map(|_| {
+                    wander(
+                        &character,
+                        &mut wander_props,
+                        Duration::from_millis(16),
+                        &mut rng,
+                    )
+                    .linear
+                    .unwrap()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(run(7), run(7));
+        assert_ne!(run(7), run(8));
+    }
+
+    #[test]
+    fn test_arrive_decelerates_near_target() {
+        let target = DummyKinematic::from_position(Vector3::new(0.0, 0.0, 0.0));
+        let mut character = DummyKinematic::from_position(Vector3::new(0.5, 0.0, 0.0));
+        character.velocity = Vector3::new(-1.0, 0.0, 0.0);
+        character.max_acceleration = 2.0;
+        character.max_speed = 1.0;
+
+        // Inside the target radius the output opposes the current velocity
+        let braking = arrive(&character, &target, 1.0, 3.0).linear.unwrap();
+        assert!(braking.dot(character.velocity) < 0.0);
+        assert!(braking.magnitude() <= character.max_acceleration + 0.0001);
+
+        // Far away the character accelerates towards the target
+        character.position = Vector3::new(10.0, 0.0, 0.0);
+        character.velocity = Vector3::new(0.0, 0.0, 0.0);
+        let accelerating = arrive(&character, &target, 1.0, 3.0).linear.unwrap();
+        assert!(accelerating.x < 0.0);
+    }
+
+    #[test]
+    fn test_pursue_and_evade_predict_target_motion() {
+        let mut character = DummyKinematic::from_position(Vector3::new(0.0, 0.0, 0.0));
+        character.max_acceleration = 1.0;
+        let mut target = DummyKinematic::from_position(Vector3::new(4.0, 0.0, 0.0));
+        target.velocity = Vector3::new(0.0, 2.0, 0.0);
+
+        // The target moves along +y, so the interception course leans towards it
+        let pursuit = pursue(&character, &target, 1.0).linear.unwrap();
+        assert!(pursuit.x > 0.0 && pursuit.y > 0.0);
+
+        let evasion = evade(&character, &target, 1.0).linear.unwrap();
+        assert!(evasion.x < 0.0 && evasion.y < 0.0);
+    }
+
+    #[test]
+    fn test_separation_and_cohesion() {
+        let mut character = DummyKinematic::from_position(Vector3::new(0.0, 0.0, 0.0));
+        character.max_acceleration = 1.0;
+        let neighbors = [
+            DummyKinematic::from_position(Vector3::new(1.0, 0.0, 0.0)).props(),
+            DummyKinematic::from_position(Vector3::new(3.0, 0.0, 0.0)).props(),
+        ];
+
+        // Only the close neighbor is within the threshold
+        let push = separation(&character, &neighbors, 2.0, 0.5).linear.unwrap();
+        assert_eq!(push, Vector3::new(-0.5, 0.0, 0.0));
+
+        let pull = cohesion(&character, &neighbors).linear.unwrap();
+        assert_eq!(pull, Vector3::new(1.0, 0.0, 0.0));
+
+        assert!(separation(&character, &[], 2.0, 0.5).linear.is_none());
+    }
+
+    #[test]
+    fn test_velocity_match_and_flock() {
+        let mut character = DummyKinematic::from_position(Vector3::new(0.0, 0.0, 0.0));
+        character.max_acceleration = 1.0;
+        let mut ahead = DummyKinematic::from_position(Vector3::new(1.0, 0.0, 0.0));
+        ahead.velocity = Vector3::new(2.0, 0.0, 0.0);
+        let mut above = DummyKinematic::from_position(Vector3::new(0.0, 3.0, 0.0));
+        above.velocity = Vector3::new(0.0, 2.0, 0.0);
+
+        // Towards the average velocity of (1, 1, 0), capped to the max acceleration
+        let matching = velocity_match(&character, &[ahead.props(), above.props()])
+            .linear
+            .unwrap();
+        let average_direction = Vector3::new(1.0, 1.0, 0.0).normalize();
+        assert!((matching.normalize() - average_direction).magnitude() < 0.0001);
+        assert!((matching.magnitude() - character.max_acceleration).abs() < 0.0001);
+
+        assert!(velocity_match(&character, &[]).linear.is_none());
+
+        // A single neighbor at x = 1 moving along +y: separation pushes (-0.5, 0, 0),
+        // cohesion pulls (1, 0, 0) and velocity matching accelerates (0, 1, 0)
+        let mut neighbor = DummyKinematic::from_position(Vector3::new(1.0, 0.0, 0.0));
+        neighbor.velocity = Vector3::new(0.0, 2.0, 0.0);
+        let flock_props = FlockProps {
+            separation_threshold: 2.0,
+            decay_coefficient: 0.5,
+            separation_weight: 2.0,
+            cohesion_weight: 1.0,
+            velocity_match_weight: 0.5,
+        };
+
+        let flocking = flock(&character, &[neighbor.props()], &flock_props)
+            .linear
+            .unwrap();
+        assert!((flocking - Vector3::new(0.0, 0.5, 0.0)).magnitude() < 0.0001);
+    }
+
+    #[test]
+    fn test_blending() {
+        let mut character = DummyKinematic::from_position(Vector3::new(0.0, 0.0, 0.0));
+        character.max_acceleration = 1.0;
+        character.max_angular_acceleration = 5.0;
+        let forward = SteeringOutput {
+            linear: Some(Vector3::new(0.0, 0.0, 1.0)),
+            angular: None,
+        };
+        let left = SteeringOutput {
+            linear: Some(Vector3::new(1.0, 0.0, 0.0)),
+            angular: Some(Vector3::new(0.0, 1.0, 0.0)),
+        };
+
+        // Missing channels don't cancel the others out, and the result is clamped
+        let blended = blend_weighted(&character, &[(forward, 0.5), (left, 2.0)]);
+        assert!((blended.linear.unwrap().magnitude() - 1.0).abs() < 0.0001);
+        assert_eq!(blended.angular, Some(Vector3::new(0.0, 2.0, 0.0)));
+
+        // The first group is too weak, so the second one is used
+        let weak = SteeringOutput {
+            linear: Some(Vector3::new(0.001, 0.0, 0.0)),
+            angular: None,
+        };
+        let prioritized =
+            blend_priority(&character, &[vec![(weak, 1.0)], vec![(forward, 1.0)]], 0.01);
+        assert_eq!(prioritized.linear, forward.linear);
+    }
+
+    #[test]
+    fn test_avoid_obstacles() {
+        let avoidance_props = AvoidanceProps {
+            lookahead: 5.0,
+            whisker_lookahead: 1.0,
+            whisker_angle: Rad(0.5),
+            avoid_distance: 3.0,
+        };
+        let sphere = Obstacle::Sphere {
+            center: Vector3::new(0.0, 0.0, 5.0),
+            radius: 1.0,
+        };
+        let mut character = DummyKinematic::from_position(Vector3::new(0.0, 0.0, 2.0));
+        character.max_acceleration = 1.0;
+
+        // Standing still there is nothing to look ahead along
+        let idle = avoid_obstacles(&character, &[sphere], &avoidance_props);
+        assert!(idle.linear.is_none() && idle.angular.is_none());
+
+        // Heading straight at the sphere, the hit at z = 4 has a normal along -z
+        character.velocity = Vector3::new(0.0, 0.0, 1.0);
+        let push = avoid_obstacles(&character, &[sphere], &avoidance_props)
+            .linear
+            .unwrap();
+        assert!((push - Vector3::new(0.0, 0.0, -1.0)).magnitude() < 0.0001);
+
+        // Out of reach of every ray
+        character.position = Vector3::new(0.0, 0.0, -10.0);
+        let clear = avoid_obstacles(&character, &[sphere], &avoidance_props);
+        assert!(clear.linear.is_none() && clear.angular.is_none());
+    }
+
+    #[test]
+    fn test_follow_path() {
+        let path = Path::new(
+            PathKind::Polyline,
+            vec![Vector3::new(0.0, 0.0, 0.0), Vector3::new(10.0, 0.0, 0.0)],
+            false,
+        );
+        let mut character = DummyKinematic::from_position(Vector3::new(2.0, 1.0, 0.0));
+        character.max_acceleration = 1.0;
+        character.max_speed = 1.0;
+
+        // The closest point is at x = 2, so the character seeks 1 further along
+        let ahead = DummyKinematic::from_position(Vector3::new(3.0, 0.0, 0.0));
+        let following = follow_path(&character, &path, 1.0).linear.unwrap();
+        assert_eq!(following, seek(&character, &ahead).linear.unwrap());
+
+        // Near the end of the open path it brakes instead of overshooting
+        character.position = Vector3::new(9.5, 0.0, 0.0);
+        character.velocity = Vector3::new(1.0, 0.0, 0.0);
+        let braking = follow_path(&character, &path, 1.0).linear.unwrap();
+        assert!(braking.x < 0.0);
+    }
+
+    #[test]
+    fn test_look_where_you_are_going() {
+        let direction = Vector3::new(1.0, 1.0, 0.0).normalize();
+        let facing = face_direction(direction) * Vector3::unit_z();
+        assert!((facing - direction).magnitude() < 0.0001);
+
+        let mut character = DummyKinematic::from_position(Vector3::new(0.0, 0.0, 0.0));
+        assert!(look_where_you_are_going(&character).angular.is_none());
+
+        character.velocity = Vector3::new(1.0, 0.0, 0.0);
+        assert!(look_where_you_are_going(&character).angular.is_some());
+    }
+
+    #[test]
+    fn test_align_turns_the_shortest_way() {
+        let mut character = DummyKinematic::from_position(Vector3::new(0.0, 0.0, 0.0));
+        character.max_angular_acceleration = 10.0;
+        character.max_rotation = 1.0;
+
+        let left = DummyKinematic::from_orientation(Quaternion::from_angle_y(Rad(1.5)));
+        let right = DummyKinematic::from_orientation(Quaternion::from_angle_y(Rad(-1.5)));
+        let pitch = DummyKinematic::from_orientation(Quaternion::from_angle_x(Rad(1.5)));
+
+        assert!(align(&character, &left).angular.unwrap().y > 0.0);
+        assert!(align(&character, &right).angular.unwrap().y < 0.0);
+        assert!(align(&character, &pitch).angular.unwrap().x > 0.0);
+        assert!(align(&character, &character).angular.is_none());
+
+        // Facing directly backwards must still produce a half turn
+        let backwards = face_direction(-Vector3::unit_z()) * Vector3::unit_z();
+        assert!((backwards + Vector3::unit_z()).magnitude() < 0.0001);
+    }
+
+    #[test]
+    fn test_align_converges() {
+        let mut ship = Spaceship::new(
+            1,
+            Vector3::new(0.0, 0.0, 0.0),
+            Quaternion::from_angle_x(Rad(0.3)),
+        );
+        let target_orientation =
+            Quaternion::from_angle_y(Rad(2.5)) * Quaternion::from_angle_z(Rad(1.0));
+        let target = DummyKinematic::from_orientation(target_orientation);
+
+        for _ in 0..600 {
+            let steering = align(&ship, &target);
+            ship.update(steering, Duration::from_millis(16));
+        }
+
+        let remaining = (ship.orientation.conjugate() * target_orientation).normalize();
+        assert!(quaternion_angle(remaining).min(quaternion_angle(-remaining)) < 0.05);
+        assert!(ship.rotation.magnitude() < 0.05);
+    }
 }
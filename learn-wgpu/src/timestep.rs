@@ -0,0 +1,68 @@
+use std::time::Duration;
+
+pub const DEFAULT_TICK_RATE: u32 = 60;
+// A long hitch is simulated as at most this much time, instead of
+// running a burst of catch-up ticks that makes the next frame slow too
+const MAX_FRAME_TIME: Duration = Duration::from_millis(250);
+
+// Splits variable frame times into a whole number of fixed simulation ticks.
+// Whatever is left over is carried to the next frame
+pub struct FixedTimestep {
+    tick: Duration,
+    accumulator: Duration,
+}
+
+impl FixedTimestep {
+    pub fn new(tick_rate: u32) -> Self {
+        FixedTimestep {
+            tick: Duration::from_nanos(1_000_000_000 / tick_rate.max(1) as u64),
+            accumulator: Duration::from_secs(0),
+        }
+    }
+
+    pub fn tick(&self) -> Duration {
+        self.tick
+    }
+
+    // How many ticks to simulate for a frame that took frame_time
+    pub fn advance(&mut self, frame_time: Duration) -> u32 {
+        self.accumulator += frame_time.min(MAX_FRAME_TIME);
+
+        let mut ticks = 0;
+
+        while self.accumulator >= self.tick {
+            self.accumulator -= self.tick;
+            ticks += 1;
+        }
+
+        ticks
+    }
+
+    // How far the current moment is from the last tick towards the next one, in the range [0, 1)
+    pub fn alpha(&self) -> f32 {
+        self.accumulator.as_secs_f32() / self.tick.as_secs_f32()
+    }
+}
+
+//
+// Tests
+//
+
+#[cfg(test)]
+mod tests {
+    use crate::timestep::FixedTimestep;
+    use std::time::Duration;
+
+    #[test]
+    fn test_fixed_timestep() {
+        let mut timestep = FixedTimestep::new(100);
+
+        assert_eq!(timestep.advance(Duration::from_millis(25)), 2);
+        assert!((timestep.alpha() - 0.5).abs() < 0.0001);
+        assert_eq!(timestep.advance(Duration::from_millis(5)), 1);
+        assert!(timestep.alpha() < 0.0001);
+
+        // A hitch is capped instead of simulated in full
+        assert_eq!(timestep.advance(Duration::from_secs(10)), 25);
+    }
+}
@@ -0,0 +1,549 @@
+use cgmath::prelude::*;
+use rand::{prelude::IteratorRandom, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::entity::{Light, Spaceship, SpaceshipState};
+use crate::obstacle::{self, Obstacle};
+use crate::spatial::SpatialGrid;
+use crate::steering::{
+    self, AvoidanceProps, DummyKinematic, FlockProps, Kinematic, SteeringOutput,
+};
+
+const CHASE_STOP_DISTANCE: f32 = 2.0;
+const CHASE_SLOW_DISTANCE: f32 = 5.0;
+// Seconds
+const MAX_PREDICTION: f32 = 1.0;
+
+const FLOCK_RADIUS: f32 = 4.0;
+const MAX_FLOCK_NEIGHBORS: usize = 7;
+const COLLISION_DISTANCE: f32 = 0.75;
+// How far ahead on its patrol path a ship aims
+const PATROL_PATH_OFFSET: f32 = 1.0;
+const ARENA_HALF_SIZE: f32 = 15.0;
+// The size of the light array in the shaders
+pub const MAX_LIGHTS: usize = 16;
+// How many ships the instance buffer has room for
+pub const MAX_SPACESHIPS: usize = 2048;
+const LIGHT_OBSTACLE_RADIUS: f32 = 0.5;
+const AVOIDANCE_PROPS: AvoidanceProps = AvoidanceProps {
+    lookahead: 3.0,
+    whisker_lookahead: 1.5,
+    whisker_angle: cgmath::Rad(0.5),
+    avoid_distance: 2.0,
+};
+// Steering outputs weaker than this fall through to the next priority group
+const PRIORITY_EPSILON: f32 = 0.01;
+const FLOCK_PROPS: FlockProps = FlockProps {
+    separation_threshold: 1.5,
+    decay_coefficient: 2.0,
+    separation_weight: 2.0,
+    cohesion_weight: 0.5,
+    velocity_match_weight: 0.5,
+};
+
+// Every simulated entity and what they steer by. Knows nothing about
+// windows or the GPU, so that it can be stepped headless
+#[derive(Clone, Serialize, Deserialize)]
+pub struct World {
+    // ordered, so that every run visits the ships in the same order
+    spaceships: BTreeMap<u16, Spaceship>,
+    // rebuilt on every step
+    #[serde(skip, default = "default_spatial_index")]
+    spatial_index: SpatialGrid,
+    obstacles: Vec<Obstacle>,
+    lights: Vec<Light>,
+    // every random choice in the simulation comes from here, so a seed reproduces a run
+    rng: ChaCha8Rng,
+    tick: u64,
+}
+
+impl World {
+    pub fn new(seed: u64) -> Self {
+        World {
+            spaceships: BTreeMap::new(),
+            spatial_index: default_spatial_index(),
+            obstacles: obstacle::arena_walls(ARENA_HALF_SIZE),
+            lights: vec![Light::new(
+                cgmath::Vector3::new(0.0, 0.0, 0.0),
+                [1.0, 0.8, 0.7],
+            )],
+            rng: ChaCha8Rng::seed_from_u64(seed),
+            tick: 0,
+        }
+    }
+
+    pub fn add_spaceship(
+        &mut self,
+        id: u16,
+        position: cgmath::Vector3<f32>,
+        orientation: cgmath::Quaternion<f32>,
+    ) {
+        self.insert_spaceship(Spaceship::new(id, position, orientation));
+    }
+
+    pub fn insert_spaceship(&mut self, spaceship: Spaceship) {
+        self.spaceships.insert(spaceship.id, spaceship);
+    }
+
+    pub fn set_lights(&mut self, lights: Vec<Light>) {
+        self.lights = lights;
+    }
+
+    pub fn set_obstacles(&mut self, obstacles: Vec<Obstacle>) {
+        self.obstacles = obstacles;
+    }
+
+    pub fn spaceships(&self) -> impl Iterator<Item = &Spaceship> {
+        self.spaceships.values()
+    }
+
+    pub fn lights(&self) -> &[Light] {
+        &self.lights
+    }
+
+    pub fn obstacles(&self) -> &[Obstacle] {
+        &self.obstacles
+    }
+
+    // How many ticks have been simulated
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    // A fingerprint of the simulated state. Built from the exact float bits with
+    // FNV-1a, so it is stable across runs, platforms and compiler versions
+    pub fn state_hash(&self) -> u64 {
+        let mut hash = StateHash::new();
+
+        hash.write_u64(self.tick);
+
+        for light in self.lights.iter() {
+            hash.write_vector(light.position);
+            hash.write_vector(light.velocity);
+        }
+
+        for spaceship in self.spaceships.values() {
+            hash.write_u64(spaceship.id as u64);
+            hash.write_vector(spaceship.position);
+            hash.write_vector(spaceship.orientation.v);
+            hash.write_f32(spaceship.orientation.s);
+            hash.write_vector(spaceship.velocity);
+            hash.write_vector(spaceship.rotation);
+
+            if let Some(animation) = &spaceship.animation {
+                hash.write_f32(animation.time);
+            }
+        }
+
+        hash.finish()
+    }
+
+    // Advance the simulation by one tick
+    pub fn step(&mut self, dt: std::time::Duration) {
+        for light in self.lights.iter_mut() {
+            light.previous_position = light.position;
+        }
+
+        for spaceship in self.spaceships.values_mut() {
+            spaceship.previous = spaceship.transform();
+
+            if let Some(animation) = &mut spaceship.animation {
+                animation.advance(dt);
+            }
+        }
+
+        // the lights
+        for light in self
+            .lights
+            .iter_mut()
+            .filter(|light| !light.is_directional())
+        {
+            chase(light, &self.spaceships, &self.obstacles, &mut self.rng, dt);
+        }
+
+        // the spaceships
+
+        // a snapshot of every ship, so that the group behaviors see the same frame
+        let flock_snapshot = self
+            .spaceships
+            .values()
+            .map(|spaceship| (spaceship.id, spaceship.props()))
+            .collect::<HashMap<_, _>>();
+
+        self.spatial_index.clear();
+
+        for (id, props) in flock_snapshot.iter() {
+            self.spatial_index.insert(*id, props.position);
+        }
+
+        let local_lights = self.lights.iter().filter(|light| !light.is_directional());
+
+        // the light markers are solid for the ships
+        let spaceship_obstacles = self
+            .obstacles
+            .iter()
+            .copied()
+            .chain(local_lights.clone().map(|light| Obstacle::Sphere {
+                center: light.position,
+                radius: LIGHT_OBSTACLE_RADIUS,
+            }))
+            .collect::<Vec<_>>();
+
+        for spaceship in self.spaceships.values_mut() {
+            // a ship only runs from the light closest to it
+            let nearest_light = local_lights.clone().min_by(|a, b| {
+                let distance_a = (spaceship.position - a.position).magnitude2();
+                let distance_b = (spaceship.position - b.position).magnitude2();
+
+                distance_a.total_cmp(&distance_b)
+            });
+            let is_near_light = nearest_light.is_some_and(|light| {
+                (spaceship.position - light.position).magnitude() < CHASE_STOP_DISTANCE * 3.0
+            });
+            let next_state = if is_near_light {
+                SpaceshipState::Fleeing
+            } else if spaceship.patrol_path.is_some() {
+                SpaceshipState::Patrolling
+            } else {
+                SpaceshipState::Wandering
+            };
+
+            spaceship.state = next_state;
+
+            // a flock member only keeps track of its closest neighbors, which
+            // keeps the cost per ship constant in a dense crowd
+            let neighbors = self
+                .spatial_index
+                .k_nearest(spaceship.position, MAX_FLOCK_NEIGHBORS + 1)
+                .iter()
+                .filter(|id| **id != spaceship.id)
+                .map(|id| flock_snapshot[id])
+                .filter(|props| (props.position - spaceship.position).magnitude() < FLOCK_RADIUS)
+                .collect::<Vec<_>>();
+            let colliding_neighbors = self
+                .spatial_index
+                .query_radius(spaceship.position, COLLISION_DISTANCE)
+                .iter()
+                .filter(|id| **id != spaceship.id)
+                .map(|id| flock_snapshot[id])
+                .collect::<Vec<_>>();
+
+            let mut wander = spaceship.wander;
+            let wander_output = steering::wander(spaceship, &mut wander, dt, &mut self.rng);
+
+            spaceship.wander = wander;
+
+            // avoiding the scene comes first, then ships about to collide
+            let obstacle_avoidance = vec![(
+                steering::avoid_obstacles(spaceship, &spaceship_obstacles, &AVOIDANCE_PROPS),
+                1.0,
+            )];
+            let collision_avoidance = vec![(
+                steering::separation(
+                    spaceship,
+                    &colliding_neighbors,
+                    COLLISION_DISTANCE,
+                    FLOCK_PROPS.decay_coefficient,
+                ),
+                1.0,
+            )];
+
+            // choose steering behavior based on the spaceship state
+            let steering_output = match spaceship.state {
+                SpaceshipState::Wandering => steering::blend_priority(
+                    spaceship,
+                    &[
+                        obstacle_avoidance,
+                        collision_avoidance,
+                        vec![
+                            (wander_output, 1.0),
+                            (steering::flock(spaceship, &neighbors, &FLOCK_PROPS), 1.0),
+                        ],
+                    ],
+                    PRIORITY_EPSILON,
+                ),
+                SpaceshipState::Fleeing => steering::blend_priority(
+                    spaceship,
+                    &[
+                        obstacle_avoidance,
+                        collision_avoidance,
+                        vec![
+                            (
+                                nearest_light.map_or(SteeringOutput::new(), |light| {
+                                    steering::evade(spaceship, light, MAX_PREDICTION)
+                                }),
+                                1.0,
+                            ),
+                            (wander_output, 0.5),
+                            (
+                                steering::separation(
+                                    spaceship,
+                                    &neighbors,
+                                    FLOCK_PROPS.separation_threshold,
+                                    FLOCK_PROPS.decay_coefficient,
+                                ),
+                                1.0,
+                            ),
+                        ],
+                    ],
+                    PRIORITY_EPSILON,
+                ),
+                SpaceshipState::Patrolling => {
+                    let follow_path_output = spaceship.patrol_path.as_ref().map_or(
+                        SteeringOutput::new(),
+                        |patrol_path| {
+                            steering::follow_path(spaceship, patrol_path, PATROL_PATH_OFFSET)
+                        },
+                    );
+
+                    steering::blend_priority(
+                        spaceship,
+                        &[
+                            obstacle_avoidance,
+                            collision_avoidance,
+                            vec![(follow_path_output, 1.0)],
+                        ],
+                        PRIORITY_EPSILON,
+                    )
+                }
+                SpaceshipState::Idle => steering::align(
+                    spaceship,
+                    &DummyKinematic::from_orientation(cgmath::Quaternion::from_angle_y(
+                        cgmath::Rad(180.0),
+                    )),
+                ),
+            };
+
+            let steering_output =
+                if spaceship.look_where_you_are_going && steering_output.angular.is_none() {
+                    SteeringOutput {
+                        linear: steering_output.linear,
+                        angular: steering::look_where_you_are_going(spaceship).angular,
+                    }
+                } else {
+                    steering_output
+                };
+
+            spaceship.update(steering_output, dt);
+        }
+
+        self.tick += 1;
+    }
+}
+
+// Move a light towards its target ship, and pick another one at random once it gets there
+fn chase(
+    light: &mut Light,
+    spaceships: &BTreeMap<u16, Spaceship>,
+    obstacles: &[Obstacle],
+    rng: &mut ChaCha8Rng,
+    dt: std::time::Duration,
+) {
+    match light.chase_target_id {
+        // chase the current target
+        Some(id) => match spaceships.get(&id) {
+            Some(spaceship) => {
+                let chase_distance = (light.position - spaceship.position).magnitude();
+
+                // intercept the target from afar, slow down once close
+                let chase_output = if chase_distance > CHASE_SLOW_DISTANCE {
+                    steering::pursue(light, spaceship, MAX_PREDICTION)
+                } else {
+                    steering::arrive(
+                        light,
+                        spaceship,
+                        CHASE_STOP_DISTANCE * 0.5,
+                        CHASE_SLOW_DISTANCE,
+                    )
+                };
+                let steering_output = steering::blend_priority(
+                    light,
+                    &[
+                        vec![(
+                            steering::avoid_obstacles(light, obstacles, &AVOIDANCE_PROPS),
+                            1.0,
+                        )],
+                        vec![(chase_output, 1.0)],
+                    ],
+                    PRIORITY_EPSILON,
+                );
+                light.update(steering_output, dt);
+                light.aim_at(spaceship.position);
+
+                let distance_to_spaceship = (light.position - spaceship.position).magnitude();
+
+                if distance_to_spaceship < CHASE_STOP_DISTANCE {
+                    let prev_id = spaceship.id;
+                    let next_target_id = spaceships.keys().filter(|k| **k != prev_id).choose(rng);
+
+                    light.chase_target_id = next_target_id.cloned();
+                };
+            }
+
+            None => (),
+        },
+
+        // no active target, choose one at random so that lights spread out
+        None => light.chase_target_id = spaceships.keys().choose(rng).cloned(),
+    }
+}
+
+fn default_spatial_index() -> SpatialGrid {
+    SpatialGrid::new(FLOCK_RADIUS)
+}
+
+struct StateHash(u64);
+
+impl StateHash {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0100_0000_01b3;
+
+    fn new() -> Self {
+        StateHash(Self::OFFSET_BASIS)
+    }
+
+    fn write_u64(&mut self, value: u64) {
+        for byte in value.to_le_bytes().iter() {
+            self.0 = (self.0 ^ *byte as u64).wrapping_mul(Self::PRIME);
+        }
+    }
+
+    fn write_f32(&mut self, value: f32) {
+        self.write_u64(value.to_bits() as u64);
+    }
+
+    fn write_vector(&mut self, vector: cgmath::Vector3<f32>) {
+        self.write_f32(vector.x);
+        self.write_f32(vector.y);
+        self.write_f32(vector.z);
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+//
+// Tests
+//
+
+#[cfg(test)]
+mod tests {
+    use crate::entity::{Light, LightKind};
+    use crate::world::World;
+    use cgmath::{prelude::*, Quaternion, Vector3};
+    use std::collections::HashSet;
+    use std::time::Duration;
+
+    fn world_with_ships(seed: u64) -> World {
+        let mut world = World::new(seed);
+
+        for id in 1..=5 {
+            world.add_spaceship(
+                id,
+                Vector3::new(id as f32 * 2.0, 0.0, 3.0),
+                Quaternion::new(1.0, 0.0, 0.0, 0.0),
+            );
+        }
+
+        world
+    }
+
+    fn positions(world: &World) -> Vec<Vector3<f32>> {
+        world
+            .spaceships()
+            .map(|spaceship| spaceship.position)
+            .chain(world.lights().iter().map(|light| light.position))
+            .collect()
+    }
+
+    #[test]
+    fn test_world_steps_deterministically() {
+        let mut first = world_with_ships(3);
+        let mut second = world_with_ships(3);
+        let start = positions(&first);
+
+        for _ in 0..300 {
+            first.step(Duration::from_millis(16));
+            second.step(Duration::from_millis(16));
+        }
+
+        assert_eq!(first.tick(), 300);
+        assert_eq!(positions(&first), positions(&second));
+
+        // Everyone got somewhere
+        for (before, after) in start.iter().zip(positions(&first)) {
+            assert!((after - before).magnitude() > 0.1);
+        }
+    }
+
+    #[test]
+    fn test_directional_lights_stay_put() {
+        let mut world = world_with_ships(5);
+        let mut sun = Light::new(Vector3::new(0.0, 0.0, 0.0), [0.5, 0.5, 0.5]);
+
+        sun.kind = LightKind::Directional {
+            direction: Vector3::new(0.0, -1.0, 0.0),
+        };
+        world.set_lights(vec![
+            sun.clone(),
+            Light::new(Vector3::new(0.0, 0.0, 0.0), [1.0; 3]),
+        ]);
+
+        for _ in 0..100 {
+            world.step(Duration::from_millis(16));
+        }
+
+        let sun_after = &world.lights()[0];
+        let chaser = &world.lights()[1];
+
+        assert_eq!(sun_after.position, sun.position);
+        assert_eq!(sun_after.color, sun.color);
+        assert!(chaser.chase_target_id.is_some());
+        assert!(chaser.position.magnitude() > 0.1);
+    }
+
+    #[test]
+    fn test_chasing_lights_keep_their_color() {
+        let mut world = world_with_ships(7);
+        let colors = [[1.0, 0.8, 0.7], [0.3, 0.5, 0.9]];
+
+        world.set_lights(
+            colors
+                .iter()
+                .map(|color| Light::new(Vector3::new(0.0, 0.0, 0.0), *color))
+                .collect(),
+        );
+
+        for _ in 0..100 {
+            world.step(Duration::from_millis(16));
+        }
+
+        for (light, color) in world.lights().iter().zip(colors.iter()) {
+            assert!(light.position.magnitude() > 0.1);
+            assert_eq!(light.color, *color);
+        }
+    }
+
+    #[test]
+    fn test_lights_pick_their_own_targets() {
+        let mut world = world_with_ships(11);
+
+        world.set_lights(
+            (0..4)
+                .map(|_| Light::new(Vector3::new(0.0, 0.0, 0.0), [1.0; 3]))
+                .collect(),
+        );
+        world.step(Duration::from_millis(16));
+
+        let targets = world
+            .lights()
+            .iter()
+            .map(|light| light.chase_target_id.unwrap())
+            .collect::<HashSet<_>>();
+
+        assert!(targets.len() > 1, "every light chases {:?}", targets);
+    }
+}